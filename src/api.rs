@@ -2,35 +2,94 @@
 API desgin:
 1. transactions:
     - GET /transactions?id=:id
-    - GET /transactions?day=:id
+    - GET /transactions?account=:pubkey&limit=100&order=asc|desc&cursor=:next_cursor
+    - GET /transactions?day=:id&limit=100&order=asc|desc&cursor=:next_cursor
     - GET /transactions?hour_start=11&hour_end=12&fee_start=100&fee_end=200
     - GET /transactions/count
     - POST /sql with body {sql: "SELECT * FROM transactions WHERE fee = 500"}
+    NOTE list endpoints and /sql stream NDJSON, one row per line, pages end with a
+         {"next_cursor": ...} line when there are more rows
+    NOTE /transactions and /sql also encode rows as CSV, Arrow IPC stream or Parquet,
+         by `format=ndjson|csv|arrow|parquet` or the Accept header. Pages in those
+         formats carry the next cursor in the X-Next-Cursor header.
 
-2. accounts:
-    TODO: implement this
+2. live:
+    - GET /stream/transactions?account=:pubkey&program=:pubkey&min_amount=:lamports&balance_change=:pubkey
+      over a websocket if the request is an upgrade, else as server-sent events
+
+3. webhooks:
+    - POST /webhooks with body {"url": ..., "secret": ..., "account"?, "program"?, "min_amount"?, "balance_change"?}
+    - GET /webhooks
+    - DELETE /webhooks/:id
+
+4. views:
+    - POST /views with body {"name": ..., "sql": "SELECT sender, sum(amount) FROM transactions GROUP BY sender"}
+    - GET /views
+    - GET /views/:name, rows encoded like /sql
+    - DELETE /views/:name
+
+5. store:
+    - GET /store/memory
+    - GET /epochs/current
+    - GET /epochs/:n, up to the epoch after the current one
+
+6. stats, rows encoded like /sql:
+    - GET /stats/transactions?bucket=1m|1h|1d
+    - GET /stats/top-accounts?by=volume|count&limit=10
+    - GET /stats/programs?limit=10
+
+7. accounts:
+    - GET /accounts/:pubkey/transactions?from=:time&to=:time&success=true|false&epoch=:n&limit=100&order=asc|desc&cursor=:next_cursor
+      the account in any key position, through the account index; `from` (inclusive) and
+      `to` (exclusive) bound `block_time`, as unix seconds or RFC 3339
 */
 use axum::{
-    body::Bytes,
-    extract::{Query, State},
-    response::{IntoResponse, Response},
-    routing::{get, post},
-    Router,
+    body::{Body, Bytes},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::{header, HeaderMap, HeaderValue},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{delete, get, post},
+    Json, Router,
 };
+use datafusion::execution::SendableRecordBatchStream;
+use futures_util::{Stream, StreamExt};
 use hyper::StatusCode;
 use log::{debug, info};
 use serde::Deserialize;
 use serde_json::json;
+use std::convert::Infallible;
 
 use crate::{
     datetime::normalize_date,
-    stream::{query_to_json, TheadSafeStreamer},
+    format::ResultFormat,
+    page::{is_base58, ndjson_stream, take_page, AccountFilter, Order, PageParams},
+    stats::{programs_sql, top_accounts_sql, transactions_sql, Bucket, RankBy},
+    stream::{
+        create_view, drop_view, epoch_summary, memory_report, query_stream, query_to_json,
+        subscribe_transactions, transaction_stream, transaction_to_json, view_stream, views,
+        EpochSummary, MemoryReport, TheadSafeStreamer,
+    },
+    subscribe::{TxSubscription, TxUpdate},
+    webhook::NewWebhook,
 };
 
 #[derive(Deserialize)]
 struct TransactionQuery {
     id: Option<String>,
+    account: Option<String>,
     day: Option<String>,
+    //NOTE paging of the list queries, not flattened as urlencoded numbers fail to flatten
+    limit: Option<usize>,
+    #[serde(default)]
+    order: Order,
+    cursor: Option<String>,
+    format: Option<String>,
     // hour_start: Option<u8>,
     // hour_end: Option<u8>,
     // fee_start: Option<u64>,
@@ -39,38 +98,120 @@ struct TransactionQuery {
 
 async fn transactions(
     State(streamer): State<TheadSafeStreamer>,
+    headers: HeaderMap,
     Query(params): Query<TransactionQuery>,
-) -> Result<String, ApiError> {
-    if let Some(id) = params.id {
-        let sql = format!("SELECT * FROM transactions WHERE signature = '{}'", id);
-        let json = query_to_json(streamer, &sql, "transactions").await?;
-        debug!("Query json: {}", json);
-        Ok(json)
-    } else if let Some(day) = params.day {
-        let table_name = "transactions";
-        let dt = normalize_date(&day)?;
-        let sql = format!(
-            "SELECT * FROM transactions WHERE cast(block_time as DATE) = '{}'",
-            dt
-        );
-        let json = query_to_json(streamer, &sql, table_name).await?;
-        debug!("Query json: {}", json);
-        Ok(json)
+) -> Result<Response, ApiError> {
+    let format = negotiate(params.format.as_deref(), &headers)?;
+    if let Some(id) = params.id.as_deref() {
+        //NOTE point lookups go through the store's indexes instead of a scan
+        let Some(format) = format else {
+            let json = transaction_to_json(streamer, id).await?;
+            debug!("Query json: {}", json);
+            return Ok(json.into_response());
+        };
+        let rows = transaction_stream(streamer, id).await?;
+        encoded_response(format, rows, None)
+    } else if let Some(account) = params.account.as_deref() {
+        if !is_base58(account) {
+            return Err(ApiError::bad_request(eyre::eyre!(
+                "invalid pubkey: {}",
+                account
+            )));
+        }
+        //NOTE answered by the account index through the filter pushdown
+        let predicate = format!("array_has(account_keys, '{}')", account);
+        transactions_page(streamer, params.page(), format, &predicate).await
+    } else if let Some(day) = params.day.as_deref() {
+        let dt = normalize_date(day)?;
+        let predicate = format!("cast(block_time as DATE) = '{}'", dt);
+        transactions_page(streamer, params.page(), format, &predicate).await
     } else {
-        // Handle GET /transactions/hour_start=11&hour_end=12&fee_start=100&fee_end=200
-        todo!("1")
+        Err(ApiError::bad_request(eyre::eyre!(
+            "one of id, account or day is required"
+        )))
+    }
+}
+
+impl TransactionQuery {
+    fn page(&self) -> PageParams {
+        PageParams {
+            limit: self.limit,
+            order: self.order,
+            cursor: self.cursor.clone(),
+        }
+    }
+}
+
+async fn transactions_page(
+    streamer: TheadSafeStreamer,
+    page: PageParams,
+    format: Option<ResultFormat>,
+    predicate: &str,
+) -> Result<Response, ApiError> {
+    let sql = page
+        .to_sql("transactions", predicate)
+        .map_err(ApiError::bad_request)?;
+    debug!("Page query: {}", sql);
+    let rows = query_stream(streamer, &sql).await?;
+    match format.unwrap_or_default() {
+        ResultFormat::Ndjson => {
+            let body = Body::from_stream(ndjson_stream(rows, Some(page.limit())));
+            Ok((
+                [(header::CONTENT_TYPE, ResultFormat::Ndjson.content_type())],
+                body,
+            )
+                .into_response())
+        }
+        format => {
+            let (rows, next_cursor) = take_page(rows, page.limit()).await?;
+            encoded_response(format, rows, next_cursor)
+        }
     }
 }
 
-struct ApiError(eyre::Error);
+fn negotiate(format: Option<&str>, headers: &HeaderMap) -> Result<Option<ResultFormat>, ApiError> {
+    let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok());
+    ResultFormat::negotiate(format, accept).map_err(|e| match format {
+        Some(_) => ApiError::bad_request(e),
+        None => ApiError(StatusCode::NOT_ACCEPTABLE, e),
+    })
+}
+
+fn encoded_response(
+    format: ResultFormat,
+    rows: SendableRecordBatchStream,
+    next_cursor: Option<String>,
+) -> Result<Response, ApiError> {
+    let mut response = Body::from_stream(format.encode(rows)?).into_response();
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    if let Some(cursor) = next_cursor {
+        //NOTE cursors are hex, always a valid header value
+        headers.insert("x-next-cursor", HeaderValue::from_str(&cursor)?);
+    }
+    Ok(response)
+}
+
+struct ApiError(StatusCode, eyre::Error);
+
+impl ApiError {
+    fn bad_request(err: eyre::Error) -> Self {
+        Self(StatusCode::BAD_REQUEST, err)
+    }
+}
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("internal error: {}", self.0),
-        )
-            .into_response()
+        let msg = match self.0 {
+            StatusCode::INTERNAL_SERVER_ERROR => format!("internal error: {}", self.1),
+            StatusCode::NOT_ACCEPTABLE => format!("not acceptable: {}", self.1),
+            StatusCode::NOT_FOUND => format!("not found: {}", self.1),
+            _ => format!("bad request: {}", self.1),
+        };
+        (self.0, msg).into_response()
     }
 }
 
@@ -79,30 +220,299 @@ where
     E: Into<eyre::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        Self(StatusCode::INTERNAL_SERVER_ERROR, err.into())
     }
 }
 
 async fn transactions_count(State(streamer): State<TheadSafeStreamer>) -> Result<String, ApiError> {
     let sql = "SELECT count(1) as count FROM transactions";
-    let json = query_to_json(streamer, sql, "transactions").await?;
+    let json = query_to_json(streamer, sql).await?;
     debug!("Query json: {}", json);
     Ok(json)
 }
 
+#[derive(Deserialize)]
+struct SqlQuery {
+    format: Option<String>,
+}
+
 //TODO checking needed, now for demo
 async fn transactions_sql(
     State(streamer): State<TheadSafeStreamer>,
+    headers: HeaderMap,
+    Query(params): Query<SqlQuery>,
     body: Bytes,
-) -> Result<String, ApiError> {
+) -> Result<Response, ApiError> {
     let sql = match String::from_utf8(body.to_vec()) {
         Ok(s) => s,
-        Err(_) => return Ok(json!({ "error": "Invalid UTF-8" }).to_string()),
+        Err(_) => {
+            return Ok(json!({ "error": "Invalid UTF-8" })
+                .to_string()
+                .into_response())
+        }
     };
 
-    let json = query_to_json(streamer, &sql, "transactions").await?;
-    debug!("Query json: {}", json);
-    Ok(json)
+    let format = negotiate(params.format.as_deref(), &headers)?.unwrap_or_default();
+    debug!("Sql query: {}", sql);
+    let rows = query_stream(streamer, &sql).await?;
+    encoded_response(format, rows, None)
+}
+
+async fn stream_transactions(
+    State(streamer): State<TheadSafeStreamer>,
+    Query(subscription): Query<TxSubscription>,
+    ws: Option<WebSocketUpgrade>,
+) -> Result<Response, ApiError> {
+    subscription.validate().map_err(ApiError::bad_request)?;
+    let updates = subscribe_transactions(streamer, subscription).await;
+    if let Some(ws) = ws {
+        return Ok(ws.on_upgrade(|socket| push_updates(socket, updates)));
+    }
+    let events = updates.map(|update| {
+        let event = match update {
+            TxUpdate::Row(row) => Event::default().data(row),
+            TxUpdate::Lagged(_) => Event::default().event("lagged").data(update.to_json()),
+        };
+        Ok::<_, Infallible>(event)
+    });
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+async fn push_updates(mut socket: WebSocket, updates: impl Stream<Item = TxUpdate> + Send) {
+    let mut updates = std::pin::pin!(updates);
+    loop {
+        tokio::select! {
+            update = updates.next() => {
+                let Some(update) = update else { break };
+                if socket.send(Message::Text(update.to_json())).await.is_err() {
+                    break;
+                }
+            }
+            //NOTE incoming messages are only watched for the client going away
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    debug!("Transaction subscriber left");
+}
+
+async fn create_webhook(
+    State(streamer): State<TheadSafeStreamer>,
+    Json(new): Json<NewWebhook>,
+) -> Result<Response, ApiError> {
+    new.validate().map_err(ApiError::bad_request)?;
+    let webhooks = streamer.read().await.webhooks.clone();
+    let hook = webhooks.write().await.create(new)?;
+    info!("Webhook {} registered for {}", hook.id, hook.url);
+    Ok((StatusCode::CREATED, Json(hook.info())).into_response())
+}
+
+async fn list_webhooks(State(streamer): State<TheadSafeStreamer>) -> Response {
+    let webhooks = streamer.read().await.webhooks.clone();
+    let hooks = webhooks.read().await.list();
+    Json(hooks.iter().map(|h| h.info()).collect::<Vec<_>>()).into_response()
+}
+
+async fn delete_webhook(
+    State(streamer): State<TheadSafeStreamer>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let webhooks = streamer.read().await.webhooks.clone();
+    let deleted = webhooks.write().await.delete(&id)?;
+    Ok(if deleted {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+#[derive(Deserialize)]
+struct NewView {
+    name: String,
+    sql: String,
+}
+
+async fn create_continuous_view(
+    State(streamer): State<TheadSafeStreamer>,
+    Json(new): Json<NewView>,
+) -> Result<Response, ApiError> {
+    let view = create_view(streamer, &new.name, &new.sql)
+        .await
+        .map_err(ApiError::bad_request)?;
+    info!("View {} registered: {}", view.name, view.sql);
+    Ok((StatusCode::CREATED, Json(view)).into_response())
+}
+
+async fn list_views(State(streamer): State<TheadSafeStreamer>) -> Response {
+    Json(views(streamer).await).into_response()
+}
+
+async fn view_rows(
+    State(streamer): State<TheadSafeStreamer>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Query(params): Query<SqlQuery>,
+) -> Result<Response, ApiError> {
+    let format = negotiate(params.format.as_deref(), &headers)?;
+    let Some(rows) = view_stream(streamer, &name).await? else {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            eyre::eyre!("no view {}", name),
+        ));
+    };
+    encoded_response(format.unwrap_or_default(), rows, None)
+}
+
+async fn drop_continuous_view(
+    State(streamer): State<TheadSafeStreamer>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    Ok(if drop_view(streamer, &name).await? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+#[derive(Deserialize)]
+struct AccountTxQuery {
+    from: Option<String>,
+    to: Option<String>,
+    success: Option<bool>,
+    epoch: Option<u64>,
+    limit: Option<usize>,
+    #[serde(default)]
+    order: Order,
+    cursor: Option<String>,
+    format: Option<String>,
+}
+
+impl AccountTxQuery {
+    //NOTE not flattened, the query string deserializer only handles flat structs
+    fn filter(&self) -> AccountFilter {
+        AccountFilter {
+            from: self.from.clone(),
+            to: self.to.clone(),
+            success: self.success,
+            epoch: self.epoch,
+        }
+    }
+}
+
+async fn account_transactions(
+    State(streamer): State<TheadSafeStreamer>,
+    headers: HeaderMap,
+    Path(pubkey): Path<String>,
+    Query(params): Query<AccountTxQuery>,
+) -> Result<Response, ApiError> {
+    if !is_base58(&pubkey) {
+        return Err(ApiError::bad_request(eyre::eyre!(
+            "invalid pubkey: {}",
+            pubkey
+        )));
+    }
+    let format = negotiate(params.format.as_deref(), &headers)?;
+    let predicate = params
+        .filter()
+        .predicate(&pubkey)
+        .map_err(ApiError::bad_request)?;
+    let page = PageParams {
+        limit: params.limit,
+        order: params.order,
+        cursor: params.cursor.clone(),
+    };
+    transactions_page(streamer, page, format, &predicate).await
+}
+
+#[derive(Deserialize)]
+struct StatsQuery {
+    #[serde(default)]
+    bucket: Bucket,
+    #[serde(default)]
+    by: RankBy,
+    limit: Option<usize>,
+    format: Option<String>,
+}
+
+async fn transaction_stats(
+    State(streamer): State<TheadSafeStreamer>,
+    headers: HeaderMap,
+    Query(params): Query<StatsQuery>,
+) -> Result<Response, ApiError> {
+    stats_response(
+        streamer,
+        &headers,
+        &params,
+        &transactions_sql(params.bucket),
+    )
+    .await
+}
+
+async fn top_accounts(
+    State(streamer): State<TheadSafeStreamer>,
+    headers: HeaderMap,
+    Query(params): Query<StatsQuery>,
+) -> Result<Response, ApiError> {
+    let sql = top_accounts_sql(params.by, params.limit);
+    stats_response(streamer, &headers, &params, &sql).await
+}
+
+async fn program_stats(
+    State(streamer): State<TheadSafeStreamer>,
+    headers: HeaderMap,
+    Query(params): Query<StatsQuery>,
+) -> Result<Response, ApiError> {
+    stats_response(streamer, &headers, &params, &programs_sql(params.limit)).await
+}
+
+async fn stats_response(
+    streamer: TheadSafeStreamer,
+    headers: &HeaderMap,
+    params: &StatsQuery,
+    sql: &str,
+) -> Result<Response, ApiError> {
+    let format = negotiate(params.format.as_deref(), headers)?;
+    debug!("Stats query: {}", sql);
+    let rows = query_stream(streamer, sql).await?;
+    encoded_response(format.unwrap_or_default(), rows, None)
+}
+
+async fn store_memory(State(streamer): State<TheadSafeStreamer>) -> Json<MemoryReport> {
+    Json(memory_report(streamer).await)
+}
+
+async fn current_epoch(
+    State(streamer): State<TheadSafeStreamer>,
+) -> Result<Json<EpochSummary>, ApiError> {
+    epoch_response(streamer, None).await
+}
+
+async fn epoch(
+    State(streamer): State<TheadSafeStreamer>,
+    Path(epoch): Path<u64>,
+) -> Result<Json<EpochSummary>, ApiError> {
+    epoch_response(streamer, Some(epoch)).await
+}
+
+//NOTE epochs past the next one have no known slots yet
+async fn epoch_response(
+    streamer: TheadSafeStreamer,
+    epoch: Option<u64>,
+) -> Result<Json<EpochSummary>, ApiError> {
+    match epoch_summary(streamer, epoch).await {
+        Some(summary) => Ok(Json(summary)),
+        None => Err(ApiError(
+            StatusCode::NOT_FOUND,
+            eyre::eyre!(
+                "no epoch {}",
+                epoch.map_or("current".into(), |n| n.to_string())
+            ),
+        )),
+    }
 }
 
 pub async fn api_server(stream: TheadSafeStreamer) -> eyre::Result<()> {
@@ -110,6 +520,18 @@ pub async fn api_server(stream: TheadSafeStreamer) -> eyre::Result<()> {
         .route("/transactions", get(transactions))
         .route("/transactions/count", get(transactions_count))
         .route("/sql", post(transactions_sql))
+        .route("/stream/transactions", get(stream_transactions))
+        .route("/webhooks", post(create_webhook).get(list_webhooks))
+        .route("/webhooks/:id", delete(delete_webhook))
+        .route("/views", post(create_continuous_view).get(list_views))
+        .route("/views/:name", get(view_rows).delete(drop_continuous_view))
+        .route("/accounts/:pubkey/transactions", get(account_transactions))
+        .route("/stats/transactions", get(transaction_stats))
+        .route("/stats/top-accounts", get(top_accounts))
+        .route("/stats/programs", get(program_stats))
+        .route("/store/memory", get(store_memory))
+        .route("/epochs/current", get(current_epoch))
+        .route("/epochs/:n", get(epoch))
         .with_state(stream);
 
     let addr = "127.0.0.1:3666";
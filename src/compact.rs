@@ -0,0 +1,172 @@
+use crate::segment::SegmentSet;
+use datafusion::arrow::{
+    array::{RecordBatch, UInt32Array},
+    compute::{cast, lexsort_to_indices, take_record_batch, SortColumn},
+    datatypes::{DataType, Field, Schema},
+};
+use log::{debug, error};
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+//NOTE high cardinality but heavily repeated within a segment, so they are stored
+//     dictionary encoded; `account_keys` stays a plain list for the account index
+pub(crate) const PUBKEY_COLUMNS: [&str; 3] = ["sender", "receiver", "err_program"];
+pub(crate) const COMPACT_INTERVAL: Duration = Duration::from_secs(5);
+pub(crate) const TARGET_SEGMENT_ROWS: usize = 1 << 16;
+
+fn pubkey_type() -> DataType {
+    DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8))
+}
+
+/// The schema of the stored transaction batches: the parsed schema with its pubkey
+/// columns dictionary encoded
+pub(crate) fn stored_schema(schema: &Schema) -> Schema {
+    let fields: Vec<Field> = schema
+        .fields()
+        .iter()
+        .map(|f| {
+            if PUBKEY_COLUMNS.contains(&f.name().as_str()) {
+                f.as_ref().clone().with_data_type(pubkey_type())
+            } else {
+                f.as_ref().clone()
+            }
+        })
+        .collect();
+    Schema::new(fields)
+}
+
+/// Dictionary encodes the pubkey columns of a transaction batch. Already encoded columns
+/// are decoded first, so the dictionaries of concatenated batches get deduplicated.
+pub(crate) fn encode_pubkeys(batch: &RecordBatch) -> eyre::Result<RecordBatch> {
+    let schema = Arc::new(stored_schema(&batch.schema()));
+    let mut columns = batch.columns().to_vec();
+    for name in PUBKEY_COLUMNS {
+        let idx = batch.schema().index_of(name)?;
+        let plain = cast(&columns[idx], &DataType::Utf8)?;
+        columns[idx] = cast(&plain, &pubkey_type())?;
+    }
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+/// Sorts a batch by slot, keeping the order of the rows within a slot. Rows of a slot
+/// share their `block_time`, so the batch ends up sorted by `block_time` as well.
+pub(crate) fn sort_by_slot(batch: &RecordBatch) -> eyre::Result<RecordBatch> {
+    let slots = batch
+        .column_by_name("slot")
+        .ok_or_else(|| eyre::eyre!("no slot column in batch"))?;
+    //NOTE the row number breaks ties, as the index addresses rows by position in their slot
+    let rows = Arc::new(UInt32Array::from_iter_values(0..batch.num_rows() as u32));
+    let indices = lexsort_to_indices(
+        &[
+            SortColumn {
+                values: slots.clone(),
+                options: None,
+            },
+            SortColumn {
+                values: rows,
+                options: None,
+            },
+        ],
+        None,
+    )?;
+    Ok(take_record_batch(batch, &indices)?)
+}
+
+/// Runs compaction rounds in the background until the task is aborted
+pub(crate) fn spawn_compactor(data: Arc<RwLock<SegmentSet>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(COMPACT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let data = data.clone();
+            //NOTE merging is cpu bound, keep it off the async workers
+            match tokio::task::spawn_blocking(move || compact(&data)).await {
+                Ok(Ok(n)) if n > 0 => debug!("--- compacted {} segments", n),
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => error!("compaction failed: {}", e),
+                Err(e) => error!("compactor panicked: {}", e),
+            }
+        }
+    })
+}
+
+/// Runs compaction until nothing is left to merge, returns the number of merged inputs.
+/// Merges are built without holding the write lock, and dropped if the store changed
+/// meanwhile; the next round picks them up again.
+pub(crate) fn compact(data: &RwLock<SegmentSet>) -> eyre::Result<usize> {
+    let mut merged = 0;
+    loop {
+        let plan = {
+            let data = data.read().unwrap_or_else(|e| e.into_inner());
+            data.compaction_plan()
+        };
+        let Some(plan) = plan else {
+            return Ok(merged);
+        };
+        let inputs = plan.len();
+        let output = plan.build()?;
+        let mut data = data.write().unwrap_or_else(|e| e.into_inner());
+        if !data.apply_compaction(output) {
+            return Ok(merged);
+        }
+        merged += inputs;
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::types::Transaction;
+    use datafusion::arrow::array::{AsArray, StringArray, UInt64Array};
+
+    #[test]
+    fn test_encode_and_sort() -> eyre::Result<()> {
+        let schema = Schema::new(vec![
+            Field::new("slot", DataType::UInt64, false),
+            Field::new("sender", DataType::Utf8, false),
+            Field::new("receiver", DataType::Utf8, true),
+            Field::new("err_program", DataType::Utf8, true),
+        ]);
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(UInt64Array::from(vec![2, 1, 2, 1])),
+                Arc::new(StringArray::from(vec!["a", "b", "a", "c"])),
+                Arc::new(StringArray::from(vec![Some("x"), None, Some("x"), None])),
+                Arc::new(StringArray::from(vec![None::<&str>; 4])),
+            ],
+        )?;
+        let batch = sort_by_slot(&encode_pubkeys(&batch)?)?;
+        let slots = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        assert_eq!(slots.values().to_vec(), vec![1, 1, 2, 2]);
+        let sender = batch
+            .column(1)
+            .as_dictionary::<datafusion::arrow::datatypes::Int32Type>();
+        assert_eq!(sender.values().len(), 3);
+        let sender = cast(batch.column(1), &DataType::Utf8)?;
+        let sender = sender.as_string::<i32>();
+        //rows of a slot keep their order
+        assert_eq!(
+            sender.iter().collect::<Vec<_>>(),
+            vec![Some("b"), Some("c"), Some("a"), Some("a")]
+        );
+
+        let stored = stored_schema(&Transaction::get_arrow_scheme());
+        assert_eq!(
+            stored.field_with_name("sender")?.data_type(),
+            &pubkey_type()
+        );
+        assert_eq!(
+            stored.field_with_name("signature")?.data_type(),
+            &DataType::Utf8
+        );
+        Ok(())
+    }
+}
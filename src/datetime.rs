@@ -1,4 +1,4 @@
-use chrono::NaiveDate;
+use chrono::{DateTime, NaiveDate};
 
 pub(crate) fn normalize_date(date_str: &str) -> eyre::Result<String> {
     let formats = [
@@ -20,6 +20,20 @@ pub(crate) fn normalize_date(date_str: &str) -> eyre::Result<String> {
     eyre::bail!("date time can not be supported");
 }
 
+/// Unix seconds of a time given as unix seconds or RFC 3339
+pub(crate) fn parse_timestamp(time_str: &str) -> eyre::Result<i64> {
+    if let Ok(secs) = time_str.parse::<i64>() {
+        return Ok(secs);
+    }
+    match DateTime::parse_from_rfc3339(time_str) {
+        Ok(time) => Ok(time.timestamp()),
+        Err(_) => eyre::bail!(
+            "invalid time, expected unix seconds or RFC 3339: {}",
+            time_str
+        ),
+    }
+}
+
 #[cfg(test)]
 mod unit_tests {
     use super::*;
@@ -46,4 +60,13 @@ mod unit_tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_parse_timestamp() -> eyre::Result<()> {
+        assert_eq!(parse_timestamp("1720353600")?, 1720353600);
+        assert_eq!(parse_timestamp("2024-07-07T12:00:00Z")?, 1720353600);
+        assert_eq!(parse_timestamp("2024-07-07T14:00:00+02:00")?, 1720353600);
+        assert!(parse_timestamp("07/07/2024").is_err());
+        Ok(())
+    }
 }
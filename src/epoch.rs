@@ -0,0 +1,107 @@
+use chrono::DateTime;
+use serde::Serialize;
+use solana_sdk::{clock::DEFAULT_MS_PER_SLOT, epoch_schedule::EpochSchedule};
+
+/// What is known about an epoch: its slot range from the schedule, how far the cluster
+/// is into it, and how much of it the store holds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EpochSummary {
+    pub epoch: u64,
+    pub first_slot: u64,
+    pub last_slot: u64,
+    pub slots_in_epoch: u64,
+    pub current_slot: u64,
+    /// share of the epoch's slots already elapsed, from 0 to 1
+    pub progress: f64,
+    /// RFC 3339, extrapolated at the nominal slot time from the latest known block time
+    pub estimated_end_time: Option<String>,
+    /// slots of the epoch with a stored block
+    pub ingested_slots: u64,
+    pub ingested_transactions: u64,
+    /// share of the elapsed slots with a stored block, skipped slots keep it below 1
+    pub ingested_ratio: f64,
+}
+
+/// Summarizes `epoch` as of `current_slot`. `anchor` is a (slot, unix time) pair to
+/// extrapolate times from, usually the latest stored block with a block time.
+/// None if the slots of the epoch do not fit in a u64.
+pub(crate) fn summarize(
+    schedule: &EpochSchedule,
+    epoch: u64,
+    current_slot: u64,
+    anchor: Option<(u64, i64)>,
+    ingested: (u64, u64),
+) -> Option<EpochSummary> {
+    //NOTE the schedule computes slots unchecked, the epoch of the last slot may be cut short
+    if epoch >= schedule.get_epoch(u64::MAX) {
+        return None;
+    }
+    let first_slot = schedule.get_first_slot_in_epoch(epoch);
+    let last_slot = schedule.get_last_slot_in_epoch(epoch);
+    let slots_in_epoch = schedule.get_slots_in_epoch(epoch);
+    let elapsed = if current_slot < first_slot {
+        0
+    } else {
+        (current_slot - first_slot + 1).min(slots_in_epoch)
+    };
+    let estimated_end_time = anchor.and_then(|(slot, time)| {
+        let remaining = i64::try_from(last_slot)
+            .ok()?
+            .checked_add(1)?
+            .checked_sub(i64::try_from(slot).ok()?)?;
+        let end_ms = time
+            .checked_mul(1000)?
+            .checked_add(remaining.checked_mul(DEFAULT_MS_PER_SLOT as i64)?)?;
+        DateTime::from_timestamp_millis(end_ms).map(|t| t.to_rfc3339())
+    });
+    let (ingested_slots, ingested_transactions) = ingested;
+    Some(EpochSummary {
+        epoch,
+        first_slot,
+        last_slot,
+        slots_in_epoch,
+        current_slot,
+        progress: elapsed as f64 / slots_in_epoch as f64,
+        estimated_end_time,
+        ingested_slots,
+        ingested_transactions,
+        ingested_ratio: if elapsed == 0 {
+            0.0
+        } else {
+            ingested_slots as f64 / elapsed as f64
+        },
+    })
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        let schedule = EpochSchedule::custom(100, 100, false);
+        let s = summarize(&schedule, 3, 324, Some((325, 1_000_000)), (20, 300)).unwrap();
+        assert_eq!(
+            (s.first_slot, s.last_slot, s.slots_in_epoch),
+            (300, 399, 100)
+        );
+        assert_eq!(s.progress, 0.25);
+        assert_eq!(s.ingested_ratio, 0.8);
+        //75 slots left at 400ms
+        assert_eq!(
+            s.estimated_end_time.as_deref(),
+            Some("1970-01-12T13:47:10+00:00")
+        );
+
+        let past = summarize(&schedule, 1, 324, None, (0, 0)).unwrap();
+        assert_eq!(past.progress, 1.0);
+        assert_eq!(past.estimated_end_time, None);
+        let future = summarize(&schedule, 4, 324, None, (0, 0)).unwrap();
+        assert_eq!((future.progress, future.ingested_ratio), (0.0, 0.0));
+
+        //out of range epochs and times do not overflow
+        assert_eq!(summarize(&schedule, u64::MAX, 324, None, (0, 0)), None);
+        let far = summarize(&schedule, 3, 324, Some((0, i64::MAX)), (0, 0)).unwrap();
+        assert_eq!(far.estimated_end_time, None);
+    }
+}
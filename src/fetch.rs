@@ -1,13 +1,13 @@
-use crate::types::{parse_transaction, Transaction};
-use datafusion::arrow::{array::RecordBatch, json::ReaderBuilder};
+use crate::types::{
+    parse_block, parse_transaction, to_record_batch, Commitment, SlotData, Transaction,
+};
+use datafusion::arrow::array::RecordBatch;
 use eyre::Ok;
 use log::trace;
 use solana_client::{rpc_client::RpcClient, rpc_config::RpcBlockConfig};
-use solana_sdk::{
-    account::Account, commitment_config::CommitmentConfig, epoch_info::EpochInfo, pubkey::Pubkey,
-};
+use solana_sdk::{account::Account, epoch_schedule::EpochSchedule, pubkey::Pubkey};
 use solana_transaction_status::{UiConfirmedBlock, UiTransactionEncoding};
-use std::{str::FromStr, sync::Arc, time::Duration};
+use std::{str::FromStr, sync::OnceLock, time::Duration};
 use tokio::time::sleep;
 
 // pub const SOL_RPC_URL_HELIUS: &str =
@@ -17,38 +17,81 @@ pub const SOL_RPC_URL: &str =
 
 pub struct SolFetcher {
     rpc_client: RpcClient,
+    commitment: Commitment,
+    epoch_schedule: OnceLock<EpochSchedule>,
 }
 
-#[derive(Debug)]
-pub(crate) struct CurrentEpoch(EpochInfo);
+/// Where a slot sits in the cluster's epoch schedule, computed locally
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct EpochPosition {
+    pub(crate) epoch: u64,
+    pub(crate) slot_index: u64,
+    pub(crate) slots_in_epoch: u64,
+    pub(crate) slot: u64,
+}
 
-impl CurrentEpoch {
-    // pub(crate) fn current_epoch(&self) -> u64 {
-    //     self.0.epoch
-    // }
+impl EpochPosition {
+    pub(crate) fn of(schedule: &EpochSchedule, slot: u64) -> Self {
+        let (epoch, slot_index) = schedule.get_epoch_and_slot_index(slot);
+        Self {
+            epoch,
+            slot_index,
+            slots_in_epoch: schedule.get_slots_in_epoch(epoch),
+            slot,
+        }
+    }
 
     pub(crate) fn start_slot(&self) -> u64 {
-        self.0.absolute_slot - self.0.slot_index
+        self.slot - self.slot_index
     }
 
     pub(crate) fn current_slot(&self) -> u64 {
-        self.0.absolute_slot
-    }
-
-    pub(crate) fn start_slot_next_epoch(&self) -> u64 {
-        self.start_slot() + self.0.slots_in_epoch
+        self.slot
     }
 }
 
 impl SolFetcher {
     pub fn new(rpc_url: &str) -> Self {
-        let rpc_client = RpcClient::new_with_commitment(rpc_url, CommitmentConfig::finalized());
-        Self { rpc_client }
+        Self::new_with_commitment(rpc_url, Commitment::Finalized)
+    }
+
+    pub fn new_with_commitment(rpc_url: &str, commitment: Commitment) -> Self {
+        let rpc_client = RpcClient::new_with_commitment(rpc_url, commitment.config());
+        Self {
+            rpc_client,
+            commitment,
+            epoch_schedule: OnceLock::new(),
+        }
+    }
+
+    /// Slots in `[start_slot, end_slot]` holding a finalized block, i.e. not skipped and not orphaned
+    pub(crate) fn get_finalized_blocks(
+        &self,
+        start_slot: u64,
+        end_slot: u64,
+    ) -> eyre::Result<Vec<u64>> {
+        let slots = self.rpc_client.get_blocks_with_commitment(
+            start_slot,
+            Some(end_slot),
+            Commitment::Finalized.config(),
+        )?;
+        Ok(slots)
+    }
+
+    ///NOTE only the current slot is fetched, the epoch is computed from the cached schedule
+    pub(crate) fn get_current_epoch(&self) -> eyre::Result<EpochPosition> {
+        let schedule = self.get_epoch_schedule()?;
+        let slot = self.rpc_client.get_slot()?;
+        Ok(EpochPosition::of(&schedule, slot))
     }
 
-    pub(crate) fn get_current_epoch(&self) -> eyre::Result<CurrentEpoch> {
-        let epoch_info = self.rpc_client.get_epoch_info()?;
-        Ok(CurrentEpoch(epoch_info))
+    /// The cluster's epoch schedule, fixed at genesis, so it is fetched once and cached
+    pub(crate) fn get_epoch_schedule(&self) -> eyre::Result<EpochSchedule> {
+        if let Some(schedule) = self.epoch_schedule.get() {
+            return Ok(schedule.clone());
+        }
+        let schedule = self.rpc_client.get_epoch_schedule()?;
+        Ok(self.epoch_schedule.get_or_init(|| schedule).clone())
     }
 
     #[allow(unused)]
@@ -96,8 +139,8 @@ impl SolFetcher {
             RpcBlockConfig {
                 encoding: Some(UiTransactionEncoding::Binary),
                 transaction_details: None,
-                rewards: None,
-                commitment: None,
+                rewards: Some(true),
+                commitment: Some(self.commitment.config()),
                 max_supported_transaction_version: Some(0),
             },
         )?;
@@ -109,7 +152,7 @@ impl SolFetcher {
         let block = self.fetch_sol_block(slot).await?;
         Ok(block.transactions.map_or(vec![], |txs| {
             txs.iter()
-                .filter_map(|tx| parse_transaction(tx, slot, block.block_time))
+                .filter_map(|tx| parse_transaction(tx, slot, block.block_time, self.commitment))
                 .collect()
         }))
     }
@@ -118,31 +161,42 @@ impl SolFetcher {
         let block = self.fetch_sol_block_sync(slot)?;
         Ok(block.transactions.map_or(vec![], |txs| {
             txs.iter()
-                .filter_map(|tx| parse_transaction(tx, slot, block.block_time))
+                .filter_map(|tx| parse_transaction(tx, slot, block.block_time, self.commitment))
                 .collect()
         }))
     }
 
     pub async fn fetch_transactions_as_batch(&self, slot: u64) -> eyre::Result<RecordBatch> {
         let txs = self.fetch_transactions(slot).await?;
-        let schema = Arc::new(Transaction::get_arrow_scheme());
-        let mut decoder = ReaderBuilder::new(schema.clone()).build_decoder()?;
-        decoder.serialize(&txs)?;
-
-        let batch = decoder.flush()?.unwrap_or(RecordBatch::new_empty(schema));
-        Ok(batch)
+        to_record_batch(&txs, Transaction::get_arrow_scheme())
     }
 
     ///NOTE sync version fetch series does not have retry mechanism
     ///     and great for historical data fetching
     pub fn fetch_transactions_as_batch_sync(&self, slot: u64) -> eyre::Result<RecordBatch> {
         let txs = self.fetch_transactions_sync(slot)?;
-        let schema = Arc::new(Transaction::get_arrow_scheme());
-        let mut decoder = ReaderBuilder::new(schema.clone()).build_decoder()?;
-        decoder.serialize(&txs)?;
+        to_record_batch(&txs, Transaction::get_arrow_scheme())
+    }
+
+    ///NOTE single shot, callers retry on their own schedule
+    pub(crate) fn fetch_slot_data_sync(&self, slot: u64) -> eyre::Result<SlotData> {
+        let block = self.fetch_sol_block_sync(slot)?;
+        Ok(block_to_slot_data(&block, slot, self.commitment))
+    }
+}
 
-        let batch = decoder.flush()?.unwrap_or(RecordBatch::new_empty(schema));
-        Ok(batch)
+fn block_to_slot_data(block: &UiConfirmedBlock, slot: u64, commitment: Commitment) -> SlotData {
+    let transactions = block.transactions.as_ref().map_or(vec![], |txs| {
+        txs.iter()
+            .filter_map(|tx| parse_transaction(tx, slot, block.block_time, commitment))
+            .collect()
+    });
+    let (block, rewards) = parse_block(block, slot, commitment);
+    SlotData {
+        commitment,
+        transactions,
+        block,
+        rewards,
     }
 }
 
@@ -158,8 +212,25 @@ mod unit_tests {
 
         println!("current_epoch: {:#?}", current_epoch);
 
-        assert!(current_epoch.0.epoch > 720);
-        assert!(current_epoch.0.absolute_slot > 311516666);
+        assert!(current_epoch.epoch > 720);
+        assert!(current_epoch.slot > 311516666);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_epoch_schedule() -> eyre::Result<()> {
+        let sol_fetcher = SolFetcher::new(SOL_RPC_URL);
+        let schedule = sol_fetcher.get_epoch_schedule()?;
+        //the local computation agrees with the node's
+        let info = sol_fetcher.rpc_client.get_epoch_info()?;
+        let position = EpochPosition::of(&schedule, info.absolute_slot);
+        assert_eq!(position.epoch, info.epoch);
+        assert_eq!(position.slot_index, info.slot_index);
+        assert_eq!(position.slots_in_epoch, info.slots_in_epoch);
+        assert_eq!(
+            schedule.get_first_slot_in_epoch(info.epoch),
+            position.start_slot()
+        );
         Ok(())
     }
 
@@ -173,6 +244,25 @@ mod unit_tests {
         Ok(())
     }
 
+    #[test]
+    fn test_get_finalized_blocks() -> eyre::Result<()> {
+        let sol_fetcher = SolFetcher::new(SOL_RPC_URL);
+        let slot = 311_516_666;
+        let slots = sol_fetcher.get_finalized_blocks(slot, slot + 10)?;
+        assert!(slots.contains(&slot));
+        assert!(slots.iter().all(|s| (slot..=slot + 10).contains(s)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_slot_data() -> eyre::Result<()> {
+        let sol_fetcher = SolFetcher::new(SOL_RPC_URL);
+        let slot = 311_516_666;
+        let data = sol_fetcher.fetch_slot_data_sync(slot)?;
+        assert_eq!(data.transactions.len(), 15);
+        Ok(())
+    }
+
     #[test]
     fn test_fetch_account_sync() -> eyre::Result<()> {
         let sol_fetcher = SolFetcher::new(SOL_RPC_URL);
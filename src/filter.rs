@@ -0,0 +1,121 @@
+use crate::types::Transaction;
+use std::collections::HashSet;
+
+/// Ingest-time transaction filters, applied to each slot's rows before they reach the store.
+/// The program filters see the programs of the top-level instructions only, as stored in
+/// `program_ids`, a program only reached through a CPI does not match.
+#[derive(Debug, Clone, Default)]
+pub struct IngestFilter {
+    /// if non-empty, keep only transactions invoking at least one of these programs
+    pub include_programs: HashSet<String>,
+    /// drop transactions invoking any of these programs
+    pub exclude_programs: HashSet<String>,
+    /// if non-empty, keep only transactions mentioning at least one of these accounts
+    pub accounts: HashSet<String>,
+    pub success_only: bool,
+    pub min_fee: Option<u64>,
+    /// minimum absolute balance change of the fee payer, in lamports
+    pub min_amount: Option<u64>,
+}
+
+impl IngestFilter {
+    pub(crate) fn matches(&self, tx: &Transaction) -> bool {
+        if self.success_only && tx.err.is_some() {
+            return false;
+        }
+        if self.min_fee.is_some_and(|min| tx.fee < min) {
+            return false;
+        }
+        if self
+            .min_amount
+            .is_some_and(|min| tx.amount.unsigned_abs() < min)
+        {
+            return false;
+        }
+        if tx
+            .program_ids
+            .iter()
+            .any(|p| self.exclude_programs.contains(p))
+        {
+            return false;
+        }
+        if !self.include_programs.is_empty()
+            && !tx
+                .program_ids
+                .iter()
+                .any(|p| self.include_programs.contains(p))
+        {
+            return false;
+        }
+        if !self.accounts.is_empty() && !tx.account_keys.iter().any(|a| self.accounts.contains(a)) {
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    fn tx(
+        programs: &[&str],
+        accounts: &[&str],
+        err: Option<&str>,
+        fee: u64,
+        amount: i64,
+    ) -> Transaction {
+        Transaction {
+            err: err.map(str::to_string),
+            fee,
+            sender: accounts[0].to_string(),
+            receiver: accounts[1].to_string(),
+            amount,
+            num_instructions: programs.len() as u32,
+            account_keys: accounts.iter().map(|a| a.to_string()).collect(),
+            program_ids: programs.iter().map(|p| p.to_string()).collect(),
+            ..Transaction::mock("sig", 1)
+        }
+    }
+
+    #[test]
+    fn test_ingest_filter() {
+        let t = tx(&["P1"], &["A", "B", "P1"], None, 5000, -10);
+        assert!(IngestFilter::default().matches(&t));
+
+        let f = IngestFilter {
+            include_programs: HashSet::from(["P1".to_string()]),
+            ..Default::default()
+        };
+        assert!(f.matches(&t));
+        let f = IngestFilter {
+            include_programs: HashSet::from(["P2".to_string()]),
+            ..Default::default()
+        };
+        assert!(!f.matches(&t));
+        let f = IngestFilter {
+            exclude_programs: HashSet::from(["P1".to_string()]),
+            ..Default::default()
+        };
+        assert!(!f.matches(&t));
+        let f = IngestFilter {
+            accounts: HashSet::from(["B".to_string()]),
+            min_fee: Some(5000),
+            min_amount: Some(10),
+            ..Default::default()
+        };
+        assert!(f.matches(&t));
+        let f = IngestFilter {
+            min_amount: Some(11),
+            ..Default::default()
+        };
+        assert!(!f.matches(&t));
+
+        let failed = tx(&["P1"], &["A", "B", "P1"], Some("err"), 5000, 0);
+        let f = IngestFilter {
+            success_only: true,
+            ..Default::default()
+        };
+        assert!(!f.matches(&failed));
+    }
+}
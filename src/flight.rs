@@ -0,0 +1,365 @@
+use arrow_flight::{
+    encode::FlightDataEncoderBuilder,
+    error::FlightError,
+    flight_service_server::{FlightService, FlightServiceServer},
+    sql::{
+        server::FlightSqlService, ActionClosePreparedStatementRequest,
+        ActionCreatePreparedStatementRequest, ActionCreatePreparedStatementResult, Any,
+        CommandGetCatalogs, CommandGetDbSchemas, CommandGetTables, CommandPreparedStatementQuery,
+        CommandStatementQuery, ProstMessageExt, SqlInfo, TicketStatementQuery,
+    },
+    Action, FlightDescriptor, FlightEndpoint, FlightInfo, HandshakeRequest, HandshakeResponse,
+    IpcMessage, SchemaAsIpc, Ticket,
+};
+use async_trait::async_trait;
+use datafusion::{
+    arrow::{array::RecordBatch, datatypes::SchemaRef, ipc::writer::IpcWriteOptions},
+    execution::SendableRecordBatchStream,
+    physical_plan::memory::MemoryStream,
+};
+use futures_util::{Stream, TryStreamExt};
+use log::{debug, info};
+use prost::{bytes::Bytes, Message};
+use std::{net::SocketAddr, pin::Pin};
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+
+use crate::{
+    store::{CATALOG_NAME, SCHEMA_NAME},
+    stream::{query_schema, query_stream, tables, TheadSafeStreamer},
+};
+
+/// Arrow Flight SQL over the store, for ADBC/JDBC clients and BI tools. Rows go out as
+/// the Arrow batches DataFusion produces, nothing is converted on the way.
+///NOTE statements are planned on `GetFlightInfo` and run again on `DoGet`, the ticket
+///     carries the SQL, so no state is kept between the two calls
+pub struct SolAggFlight {
+    streamer: TheadSafeStreamer,
+}
+
+impl SolAggFlight {
+    pub fn new(streamer: TheadSafeStreamer) -> Self {
+        Self { streamer }
+    }
+
+    async fn statement_info(
+        &self,
+        sql: &str,
+        ticket: impl ProstMessageExt,
+        descriptor: FlightDescriptor,
+    ) -> Result<Response<FlightInfo>, Status> {
+        debug!("Flight SQL plan: {}", sql);
+        let schema = query_schema(self.streamer.clone(), sql)
+            .await
+            .map_err(invalid_argument)?;
+        flight_info(&schema, ticket, descriptor)
+    }
+
+    async fn statement_rows(
+        &self,
+        sql: &str,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        debug!("Flight SQL query: {}", sql);
+        let batches = query_stream(self.streamer.clone(), sql)
+            .await
+            .map_err(invalid_argument)?;
+        Ok(flight_stream(batches))
+    }
+}
+
+#[async_trait]
+impl FlightSqlService for SolAggFlight {
+    type FlightService = SolAggFlight;
+
+    async fn do_handshake(
+        &self,
+        _request: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<
+        Response<Pin<Box<dyn Stream<Item = Result<HandshakeResponse, Status>> + Send>>>,
+        Status,
+    > {
+        //NOTE no authentication, like the REST API the server only listens on localhost
+        let response = HandshakeResponse {
+            protocol_version: 0,
+            payload: Bytes::new(),
+        };
+        Ok(Response::new(Box::pin(futures_util::stream::iter([Ok(
+            response,
+        )]))))
+    }
+
+    async fn get_flight_info_statement(
+        &self,
+        query: CommandStatementQuery,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let ticket = TicketStatementQuery {
+            statement_handle: query.query.clone().into(),
+        };
+        self.statement_info(&query.query, ticket, request.into_inner())
+            .await
+    }
+
+    async fn do_get_statement(
+        &self,
+        ticket: TicketStatementQuery,
+        _request: Request<Ticket>,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        let sql = handle_to_sql(&ticket.statement_handle)?;
+        self.statement_rows(&sql).await
+    }
+
+    //NOTE a prepared statement handle is its SQL, parameters are not supported
+    async fn do_action_create_prepared_statement(
+        &self,
+        query: ActionCreatePreparedStatementRequest,
+        _request: Request<Action>,
+    ) -> Result<ActionCreatePreparedStatementResult, Status> {
+        let schema = query_schema(self.streamer.clone(), &query.query)
+            .await
+            .map_err(invalid_argument)?;
+        Ok(ActionCreatePreparedStatementResult {
+            prepared_statement_handle: query.query.into(),
+            dataset_schema: schema_to_ipc(&schema)?,
+            parameter_schema: Bytes::new(),
+        })
+    }
+
+    async fn do_action_close_prepared_statement(
+        &self,
+        _query: ActionClosePreparedStatementRequest,
+        _request: Request<Action>,
+    ) -> Result<(), Status> {
+        Ok(())
+    }
+
+    async fn get_flight_info_prepared_statement(
+        &self,
+        cmd: CommandPreparedStatementQuery,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let sql = handle_to_sql(&cmd.prepared_statement_handle)?;
+        self.statement_info(&sql, cmd, request.into_inner()).await
+    }
+
+    async fn do_get_prepared_statement(
+        &self,
+        query: CommandPreparedStatementQuery,
+        _request: Request<Ticket>,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        let sql = handle_to_sql(&query.prepared_statement_handle)?;
+        self.statement_rows(&sql).await
+    }
+
+    async fn get_flight_info_catalogs(
+        &self,
+        query: CommandGetCatalogs,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let schema = query.clone().into_builder().schema();
+        flight_info(&schema, query, request.into_inner())
+    }
+
+    async fn do_get_catalogs(
+        &self,
+        query: CommandGetCatalogs,
+        _request: Request<Ticket>,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        let mut builder = query.into_builder();
+        builder.append(CATALOG_NAME);
+        batch_stream(builder.build().map_err(internal)?)
+    }
+
+    async fn get_flight_info_schemas(
+        &self,
+        query: CommandGetDbSchemas,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let schema = query.clone().into_builder().schema();
+        flight_info(&schema, query, request.into_inner())
+    }
+
+    async fn do_get_schemas(
+        &self,
+        query: CommandGetDbSchemas,
+        _request: Request<Ticket>,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        let mut builder = query.into_builder();
+        builder.append(CATALOG_NAME, SCHEMA_NAME);
+        batch_stream(builder.build().map_err(internal)?)
+    }
+
+    async fn get_flight_info_tables(
+        &self,
+        query: CommandGetTables,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let schema = query.clone().into_builder().schema();
+        flight_info(&schema, query, request.into_inner())
+    }
+
+    async fn do_get_tables(
+        &self,
+        query: CommandGetTables,
+        _request: Request<Ticket>,
+    ) -> Result<Response<<Self as FlightService>::DoGetStream>, Status> {
+        let mut builder = query.into_builder();
+        for (name, schema) in tables(self.streamer.clone()).await.map_err(internal)? {
+            builder
+                .append(CATALOG_NAME, SCHEMA_NAME, name, "TABLE", &schema)
+                .map_err(internal)?;
+        }
+        batch_stream(builder.build().map_err(internal)?)
+    }
+
+    async fn register_sql_info(&self, _id: i32, _result: &SqlInfo) {}
+}
+
+pub async fn flight_sql_server(streamer: TheadSafeStreamer, port: u16) -> eyre::Result<()> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    info!(
+        "SolAgg Flight SQL server is starting at grpc://{} ...",
+        addr
+    );
+    Server::builder()
+        .add_service(FlightServiceServer::new(SolAggFlight::new(streamer)))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+fn flight_info(
+    schema: &SchemaRef,
+    ticket: impl ProstMessageExt,
+    descriptor: FlightDescriptor,
+) -> Result<Response<FlightInfo>, Status> {
+    let ticket = Ticket::new(ticket.as_any().encode_to_vec());
+    let info = FlightInfo::new()
+        .try_with_schema(schema)
+        .map_err(internal)?
+        .with_endpoint(FlightEndpoint::new().with_ticket(ticket))
+        .with_descriptor(descriptor);
+    Ok(Response::new(info))
+}
+
+fn flight_stream(
+    batches: SendableRecordBatchStream,
+) -> Response<<SolAggFlight as FlightService>::DoGetStream> {
+    let schema = batches.schema();
+    let batches = batches.map_err(|e| FlightError::ExternalError(Box::new(e)));
+    let data = FlightDataEncoderBuilder::new()
+        .with_schema(schema)
+        .build(batches)
+        .map_err(Status::from);
+    Response::new(Box::pin(data))
+}
+
+fn batch_stream(
+    batch: RecordBatch,
+) -> Result<Response<<SolAggFlight as FlightService>::DoGetStream>, Status> {
+    let batches =
+        MemoryStream::try_new(vec![batch.clone()], batch.schema(), None).map_err(internal)?;
+    Ok(flight_stream(Box::pin(batches)))
+}
+
+fn handle_to_sql(handle: &[u8]) -> Result<String, Status> {
+    String::from_utf8(handle.to_vec()).map_err(invalid_argument)
+}
+
+fn schema_to_ipc(schema: &SchemaRef) -> Result<Bytes, Status> {
+    let IpcMessage(bytes) = SchemaAsIpc::new(schema, &IpcWriteOptions::default())
+        .try_into()
+        .map_err(internal)?;
+    Ok(bytes)
+}
+
+fn invalid_argument(e: impl std::fmt::Display) -> Status {
+    Status::invalid_argument(e.to_string())
+}
+
+fn internal(e: impl std::fmt::Display) -> Status {
+    Status::internal(e.to_string())
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::{
+        store::{StoreConfig, TransactionStore},
+        stream::SolAggStreamer,
+    };
+    use arrow_flight::decode::FlightRecordBatchStream;
+    use datafusion::arrow::array::{AsArray, Int64Array};
+
+    fn service() -> SolAggFlight {
+        let tx_store = TransactionStore::mock(StoreConfig::default());
+        SolAggFlight::new(SolAggStreamer::with_store(tx_store))
+    }
+
+    async fn rows(
+        response: Response<<SolAggFlight as FlightService>::DoGetStream>,
+    ) -> eyre::Result<Vec<RecordBatch>> {
+        let data = response.into_inner().map_err(FlightError::from);
+        Ok(FlightRecordBatchStream::new_from_flight_data(data)
+            .try_collect()
+            .await?)
+    }
+
+    #[tokio::test]
+    async fn test_statement() -> eyre::Result<()> {
+        let flight = service();
+        let sql = "SELECT count(1) AS n FROM transactions";
+        let info = flight
+            .get_flight_info_statement(
+                CommandStatementQuery {
+                    query: sql.to_string(),
+                    transaction_id: None,
+                },
+                Request::new(FlightDescriptor::new_cmd(vec![])),
+            )
+            .await?
+            .into_inner();
+        assert_eq!(info.clone().try_decode_schema()?.field(0).name(), "n");
+
+        let ticket = info.endpoint[0].ticket.clone().unwrap();
+        let ticket = Any::decode(ticket.ticket)?
+            .unpack::<TicketStatementQuery>()?
+            .unwrap();
+        let batches = rows(
+            flight
+                .do_get_statement(ticket, Request::new(Ticket::default()))
+                .await?,
+        )
+        .await?;
+        let n = batches[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<Int64Array>()
+            .unwrap();
+        assert_eq!(n.value(0), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_tables() -> eyre::Result<()> {
+        let flight = service();
+        let query = CommandGetTables {
+            catalog: None,
+            db_schema_filter_pattern: None,
+            table_name_filter_pattern: Some("trans%".to_string()),
+            table_types: vec![],
+            include_schema: false,
+        };
+        let batches = rows(
+            flight
+                .do_get_tables(query, Request::new(Ticket::default()))
+                .await?,
+        )
+        .await?;
+        let names = batches[0]
+            .column_by_name("table_name")
+            .unwrap()
+            .as_string::<i32>();
+        assert_eq!(names.iter().collect::<Vec<_>>(), vec![Some("transactions")]);
+        Ok(())
+    }
+}
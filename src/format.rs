@@ -0,0 +1,283 @@
+use crate::page::ndjson_stream;
+use axum::body::Bytes;
+use datafusion::{
+    arrow::{
+        array::{Array, ArrayRef, RecordBatch, StringArray},
+        csv,
+        datatypes::{DataType, Field, Schema, SchemaRef},
+        ipc::writer::StreamWriter,
+        util::display::{ArrayFormatter, FormatOptions},
+    },
+    error::Result,
+    execution::SendableRecordBatchStream,
+    parquet::{arrow::ArrowWriter, file::properties::WriterProperties},
+};
+use futures_util::{stream::BoxStream, StreamExt};
+use std::sync::Arc;
+
+//NOTE rows of a Parquet row group are buffered until it is full
+const PARQUET_ROW_GROUP_ROWS: usize = 1 << 16;
+
+/// Encodings of query results, picked from the `format` query parameter or the `Accept`
+/// header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ResultFormat {
+    #[default]
+    Ndjson,
+    Csv,
+    /// Arrow IPC streaming format
+    Arrow,
+    Parquet,
+}
+
+impl ResultFormat {
+    /// The format asked for, if any. `format` wins over `accept`, an `Accept` header with
+    /// nothing supported is an error; JSON and plain text ask for the endpoint's default.
+    pub(crate) fn negotiate(
+        format: Option<&str>,
+        accept: Option<&str>,
+    ) -> eyre::Result<Option<Self>> {
+        if let Some(format) = format {
+            return match format.to_ascii_lowercase().as_str() {
+                "ndjson" | "jsonl" => Ok(Some(Self::Ndjson)),
+                "csv" => Ok(Some(Self::Csv)),
+                "arrow" => Ok(Some(Self::Arrow)),
+                "parquet" => Ok(Some(Self::Parquet)),
+                _ => Err(eyre::eyre!("unsupported format: {}", format)),
+            };
+        }
+        let Some(accept) = accept else {
+            return Ok(None);
+        };
+        let mut ranges = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let media_type = parts.next()?.to_ascii_lowercase();
+                let q = parts
+                    .filter_map(|p| p.strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (q > 0.0).then_some((media_type, q))
+            })
+            .collect::<Vec<_>>();
+        //NOTE stable, so ranges of the same quality keep the client's order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        for (media_type, _) in ranges {
+            match media_type.as_str() {
+                "*/*" | "application/*" | "application/json" | "text/plain" => return Ok(None),
+                "application/x-ndjson" | "application/jsonl" => return Ok(Some(Self::Ndjson)),
+                "text/csv" | "text/*" => return Ok(Some(Self::Csv)),
+                "application/vnd.apache.arrow.stream" => return Ok(Some(Self::Arrow)),
+                "application/vnd.apache.parquet" | "application/x-parquet" => {
+                    return Ok(Some(Self::Parquet))
+                }
+                _ => {}
+            }
+        }
+        Err(eyre::eyre!("no supported format in Accept: {}", accept))
+    }
+
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Self::Ndjson => "application/x-ndjson",
+            Self::Csv => "text/csv",
+            Self::Arrow => "application/vnd.apache.arrow.stream",
+            Self::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    /// Every row of `batches`, encoded a batch at a time as they are produced
+    pub(crate) fn encode(
+        self,
+        batches: SendableRecordBatchStream,
+    ) -> Result<BoxStream<'static, Result<Bytes>>> {
+        let schema = batches.schema();
+        let encoder: Box<dyn BatchEncoder> = match self {
+            Self::Ndjson => return Ok(ndjson_stream(batches, None).boxed()),
+            Self::Csv => Box::new(CsvEncoder { header: true }),
+            Self::Arrow => Box::new(StreamWriter::try_new(Vec::new(), &schema)?),
+            Self::Parquet => {
+                let props = WriterProperties::builder()
+                    .set_max_row_group_size(PARQUET_ROW_GROUP_ROWS)
+                    .build();
+                Box::new(ArrowWriter::try_new(Vec::new(), schema, Some(props))?)
+            }
+        };
+        let chunks = futures_util::stream::unfold(Some((batches, encoder)), |state| async move {
+            let (mut batches, mut encoder) = state?;
+            match batches.next().await {
+                Some(Ok(batch)) => match encoder.write(&batch) {
+                    Ok(chunk) => Some((Ok(chunk), Some((batches, encoder)))),
+                    Err(e) => Some((Err(e), None)),
+                },
+                Some(Err(e)) => Some((Err(e), None)),
+                None => Some((encoder.finish(), None)),
+            }
+        });
+        Ok(chunks.boxed())
+    }
+}
+
+/// Writes batches into an in-memory buffer, handing out what was written so far
+trait BatchEncoder: Send {
+    fn write(&mut self, batch: &RecordBatch) -> Result<Bytes>;
+    fn finish(self: Box<Self>) -> Result<Bytes>;
+}
+
+struct CsvEncoder {
+    header: bool,
+}
+
+impl BatchEncoder for CsvEncoder {
+    fn write(&mut self, batch: &RecordBatch) -> Result<Bytes> {
+        let mut writer = csv::WriterBuilder::new()
+            .with_header(std::mem::take(&mut self.header))
+            .build(Vec::new());
+        writer.write(&flatten_nested(batch)?)?;
+        Ok(Bytes::from(writer.into_inner()))
+    }
+
+    fn finish(self: Box<Self>) -> Result<Bytes> {
+        Ok(Bytes::new())
+    }
+}
+
+impl BatchEncoder for StreamWriter<Vec<u8>> {
+    fn write(&mut self, batch: &RecordBatch) -> Result<Bytes> {
+        StreamWriter::write(self, batch)?;
+        //NOTE the first chunk carries the schema message as well
+        Ok(Bytes::from(std::mem::take(self.get_mut())))
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<Bytes> {
+        StreamWriter::finish(&mut self)?;
+        Ok(Bytes::from(std::mem::take(self.get_mut())))
+    }
+}
+
+impl BatchEncoder for ArrowWriter<Vec<u8>> {
+    fn write(&mut self, batch: &RecordBatch) -> Result<Bytes> {
+        ArrowWriter::write(self, batch)?;
+        //NOTE only full row groups have been written out, the writer tracks offsets itself
+        Ok(Bytes::from(std::mem::take(self.inner_mut())))
+    }
+
+    fn finish(self: Box<Self>) -> Result<Bytes> {
+        Ok(Bytes::from(self.into_inner()?))
+    }
+}
+
+/// CSV has no nested values, lists and structs are rendered as text, e.g. `[a, b]`
+fn flatten_nested(batch: &RecordBatch) -> Result<RecordBatch> {
+    if !batch.columns().iter().any(|c| c.data_type().is_nested()) {
+        return Ok(batch.clone());
+    }
+    let options = FormatOptions::default();
+    let mut fields = Vec::with_capacity(batch.num_columns());
+    let mut columns = Vec::with_capacity(batch.num_columns());
+    for (field, column) in batch.schema().fields().iter().zip(batch.columns()) {
+        if !column.data_type().is_nested() {
+            fields.push(field.as_ref().clone());
+            columns.push(column.clone());
+            continue;
+        }
+        let formatter = ArrayFormatter::try_new(column.as_ref(), &options)?;
+        let text: StringArray = (0..column.len())
+            .map(|i| column.is_valid(i).then(|| formatter.value(i).to_string()))
+            .collect();
+        fields.push(Field::new(
+            field.name(),
+            DataType::Utf8,
+            field.is_nullable(),
+        ));
+        columns.push(Arc::new(text) as ArrayRef);
+    }
+    let schema: SchemaRef = Arc::new(Schema::new(fields));
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use datafusion::{
+        arrow::{array::UInt64Array, ipc::reader::StreamReader},
+        parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder,
+        physical_plan::memory::MemoryStream,
+    };
+    use futures_util::TryStreamExt;
+
+    #[test]
+    fn test_negotiate() -> eyre::Result<()> {
+        use ResultFormat::*;
+        assert_eq!(ResultFormat::negotiate(None, None)?, None);
+        assert_eq!(
+            ResultFormat::negotiate(Some("csv"), Some("text/html"))?,
+            Some(Csv)
+        );
+        assert_eq!(
+            ResultFormat::negotiate(
+                None,
+                Some("text/csv;q=0.5, application/vnd.apache.arrow.stream")
+            )?,
+            Some(Arrow)
+        );
+        assert_eq!(
+            ResultFormat::negotiate(
+                None,
+                Some("text/html, application/vnd.apache.parquet;q=0.9, */*;q=0.1")
+            )?,
+            Some(Parquet)
+        );
+        assert_eq!(ResultFormat::negotiate(None, Some("text/html, */*"))?, None);
+        assert_eq!(
+            ResultFormat::negotiate(None, Some("application/json"))?,
+            None
+        );
+        assert_eq!(ResultFormat::negotiate(None, Some("text/plain"))?, None);
+        assert_eq!(
+            ResultFormat::negotiate(None, Some("text/csv;q=0.5, application/json"))?,
+            None
+        );
+        assert!(ResultFormat::negotiate(None, Some("text/html")).is_err());
+        assert!(ResultFormat::negotiate(Some("xml"), None).is_err());
+        Ok(())
+    }
+
+    async fn encode(format: ResultFormat, batch: &RecordBatch) -> eyre::Result<Vec<u8>> {
+        let batches =
+            MemoryStream::try_new(vec![batch.clone(), batch.clone()], batch.schema(), None)?;
+        let chunks: Vec<_> = format.encode(Box::pin(batches))?.try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    #[tokio::test]
+    async fn test_encode() -> eyre::Result<()> {
+        let batch = crate::types::to_record_batch(
+            &[serde_json::json!({"slot": 7, "keys": ["a", "b"]})],
+            Schema::new(vec![
+                Field::new("slot", DataType::UInt64, false),
+                Field::new_list("keys", Field::new("item", DataType::Utf8, true), true),
+            ]),
+        )?;
+
+        let csv = String::from_utf8(encode(ResultFormat::Csv, &batch).await?)?;
+        assert_eq!(csv, "slot,keys\n7,\"[a, b]\"\n7,\"[a, b]\"\n");
+
+        let ipc = encode(ResultFormat::Arrow, &batch).await?;
+        let read = StreamReader::try_new(ipc.as_slice(), None)?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(read, vec![batch.clone(), batch.clone()]);
+
+        let parquet = encode(ResultFormat::Parquet, &batch).await?;
+        let read = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(parquet))?
+            .build()?
+            .collect::<Result<Vec<_>, _>>()?;
+        let slots = read[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap();
+        assert_eq!(slots.values(), &[7, 7]);
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use datafusion::arrow::array::{Array, AsArray, RecordBatch};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// A transaction row addressed by its slot and its position among the rows of that slot.
+/// Rows of a slot never move relative to each other, so positions survive coalescing.
+pub(crate) type RowPos = (u64, u32);
+
+/// Hash indexes over the `transactions` rows of the store
+#[derive(Debug, Default)]
+pub(crate) struct TxIndex {
+    signatures: HashMap<String, RowPos>,
+    //NOTE every key position is indexed, `sender` and `receiver` included; rows are
+    //     grouped by slot so a slot is dropped at once
+    accounts: HashMap<String, BTreeMap<u64, Vec<u32>>>,
+}
+
+impl TxIndex {
+    pub(crate) fn clear(&mut self) {
+        self.signatures.clear();
+        self.accounts.clear();
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.signatures.len()
+    }
+
+    pub(crate) fn signature(&self, signature: &str) -> Option<RowPos> {
+        self.signatures.get(signature).copied()
+    }
+
+    /// Rows holding `pubkey` in any key position, in slot order
+    pub(crate) fn account(&self, pubkey: &str) -> Vec<RowPos> {
+        self.accounts.get(pubkey).map_or(vec![], |slots| {
+            slots
+                .iter()
+                .flat_map(|(slot, rows)| rows.iter().map(|row| (*slot, *row)))
+                .collect()
+        })
+    }
+
+    /// Indexes the transaction rows of a single slot
+    pub(crate) fn insert(&mut self, slot: u64, batch: &RecordBatch) -> eyre::Result<()> {
+        for_each_row(batch, |row, signature, keys| {
+            let pos = (slot, row as u32);
+            self.signatures.insert(signature.to_string(), pos);
+            for key in keys {
+                let slots = self.accounts.entry(key.to_string()).or_default();
+                slots.entry(slot).or_default().push(pos.1);
+            }
+        })
+    }
+
+    /// Drops the index entries of a single slot, `batch` being the rows it was indexed with
+    pub(crate) fn remove(&mut self, slot: u64, batch: &RecordBatch) -> eyre::Result<()> {
+        let mut slot_keys = HashSet::new();
+        for_each_row(batch, |_, signature, keys| {
+            //NOTE the same signature may have landed on another fork since
+            if self.signatures.get(signature).is_some_and(|p| p.0 == slot) {
+                self.signatures.remove(signature);
+            }
+            slot_keys.extend(keys.into_iter().map(str::to_string));
+        })?;
+        for key in slot_keys {
+            if let Some(slots) = self.accounts.get_mut(&key) {
+                slots.remove(&slot);
+                if slots.is_empty() {
+                    self.accounts.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn for_each_row(
+    batch: &RecordBatch,
+    mut f: impl FnMut(usize, &str, Vec<&str>),
+) -> eyre::Result<()> {
+    let signatures = batch
+        .column_by_name("signature")
+        .and_then(|c| c.as_string_opt::<i32>())
+        .ok_or_else(|| eyre::eyre!("no signature column in batch"))?;
+    let keys = batch
+        .column_by_name("account_keys")
+        .and_then(|c| c.as_list_opt::<i32>())
+        .ok_or_else(|| eyre::eyre!("no account_keys column in batch"))?;
+    for row in 0..batch.num_rows() {
+        let row_keys = if keys.is_null(row) {
+            vec![]
+        } else {
+            let list = keys.value(row);
+            let list = list.as_string::<i32>();
+            (0..list.len())
+                .filter(|i| !list.is_null(*i))
+                .map(|i| list.value(i).to_string())
+                .collect::<Vec<_>>()
+        };
+        f(
+            row,
+            signatures.value(row),
+            row_keys.iter().map(|k| k.as_str()).collect(),
+        );
+    }
+    Ok(())
+}
@@ -1,6 +1,21 @@
 pub mod api;
+pub(crate) mod compact;
 pub(crate) mod datetime;
+pub(crate) mod epoch;
 pub mod fetch;
+pub(crate) mod filter;
+pub mod flight;
+pub(crate) mod format;
+pub(crate) mod index;
+pub(crate) mod page;
+pub mod pg;
+pub(crate) mod provider;
+pub(crate) mod retention;
+pub(crate) mod segment;
+pub(crate) mod stats;
 pub(crate) mod store;
 pub mod stream;
+pub(crate) mod subscribe;
 pub(crate) mod types;
+pub(crate) mod view;
+pub(crate) mod webhook;
@@ -3,8 +3,14 @@ use env_logger::Env;
 use log::{info, trace};
 use solagg::{
     api::api_server,
-    stream::{start_streamer, SolAggStreamer},
+    flight::flight_sql_server,
+    pg::pg_server,
+    stream::{
+        start_streamer, Commitment, IngestFilter, Retention, SolAggStreamer, StoreConfig,
+        VotePolicy,
+    },
 };
+use std::{path::PathBuf, time::Duration};
 
 #[derive(Parser)]
 #[clap(name = "SolAgg")]
@@ -24,7 +30,60 @@ enum Cmd {
 }
 
 #[derive(Args, Debug)]
-pub struct Start {}
+pub struct Start {
+    /// the commitment level to ingest slots at.
+    #[clap(long, value_enum, default_value_t = Commitment::Finalized)]
+    commitment: Commitment,
+    /// how to store vote transactions.
+    #[clap(long, value_enum, default_value_t = VotePolicy::Keep)]
+    votes: VotePolicy,
+    /// only ingest transactions invoking one of these programs from a top-level instruction.
+    #[clap(long = "include-program")]
+    include_programs: Vec<String>,
+    /// skip transactions invoking any of these programs from a top-level instruction.
+    #[clap(long = "exclude-program")]
+    exclude_programs: Vec<String>,
+    /// only ingest transactions mentioning one of these accounts.
+    #[clap(long = "account")]
+    accounts: Vec<String>,
+    /// only ingest successful transactions.
+    #[clap(long)]
+    success_only: bool,
+    /// only ingest transactions paying at least this fee, in lamports.
+    #[clap(long)]
+    min_fee: Option<u64>,
+    /// only ingest transactions moving at least this amount, in lamports.
+    #[clap(long)]
+    min_amount: Option<u64>,
+    /// also store the program log messages of transactions, they dominate the row size.
+    #[clap(long)]
+    keep_logs: bool,
+    /// number of epochs to keep in memory, the current one included, 0 keeps all;
+    /// the default keeps the previous epoch queryable past the epoch boundary.
+    #[clap(long, default_value_t = 2)]
+    retain_epochs: usize,
+    /// number of most recent slots to keep in memory.
+    #[clap(long)]
+    retain_slots: Option<u64>,
+    /// drop slots whose block time is older than this many seconds.
+    #[clap(long)]
+    retain_secs: Option<u64>,
+    /// memory budget for the stored batches, in MiB.
+    #[clap(long)]
+    memory_budget_mb: Option<usize>,
+    /// write evicted slots as Parquet files under this directory instead of deleting them.
+    #[clap(long)]
+    evict_to: Option<PathBuf>,
+    /// also serve Arrow Flight SQL on this port.
+    #[clap(long)]
+    flight_port: Option<u16>,
+    /// also serve read-only queries over the PostgreSQL wire protocol on this port.
+    #[clap(long)]
+    pg_port: Option<u16>,
+    /// where the webhook registry and the dead-letter log of failed deliveries are kept.
+    #[clap(long, default_value = "./webhooks")]
+    webhook_dir: PathBuf,
+}
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
@@ -36,15 +95,52 @@ async fn main() -> eyre::Result<()> {
 
     let cmd = SolAggCmd::parse();
     match cmd.cmd {
-        Cmd::Start(_) => {
+        Cmd::Start(start) => {
             info!("Starting SolAgg server...");
-            let streamer = SolAggStreamer::new_arc(true)?;
+            let config = StoreConfig {
+                vote_policy: start.votes,
+                filter: IngestFilter {
+                    include_programs: start.include_programs.into_iter().collect(),
+                    exclude_programs: start.exclude_programs.into_iter().collect(),
+                    accounts: start.accounts.into_iter().collect(),
+                    success_only: start.success_only,
+                    min_fee: start.min_fee,
+                    min_amount: start.min_amount,
+                },
+                commitment: start.commitment,
+                retention: Retention {
+                    epochs: Some(start.retain_epochs),
+                    slots: start.retain_slots,
+                    max_age: start.retain_secs.map(Duration::from_secs),
+                    max_bytes: start.memory_budget_mb.map(|mb| mb << 20),
+                    evict_to: start.evict_to,
+                },
+                keep_logs: start.keep_logs,
+            };
+            let streamer = SolAggStreamer::new_arc(true, config)?;
+            streamer.write().await.load_webhooks(start.webhook_dir)?;
             info!("SolAgg streamer initialized.");
             let s = streamer.clone();
             info!("To start SolAgg streamer...");
             let fut_streamer = tokio::spawn(async move { start_streamer(s).await });
             let s = streamer.clone();
             let fut_api_srv = tokio::spawn(async move { api_server(s).await });
+            if let Some(port) = start.flight_port {
+                let s = streamer.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = flight_sql_server(s, port).await {
+                        log::error!("Flight SQL server failed; error = {}", err);
+                    }
+                });
+            }
+            if let Some(port) = start.pg_port {
+                let s = streamer.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = pg_server(s, port).await {
+                        log::error!("PostgreSQL endpoint failed; error = {}", err);
+                    }
+                });
+            }
             let res = tokio::try_join!(fut_streamer, fut_api_srv);
             match res {
                 Ok((res0, res1)) => {
@@ -0,0 +1,272 @@
+use crate::datetime::parse_timestamp;
+use axum::body::Bytes;
+use datafusion::{
+    arrow::{
+        array::{AsArray, RecordBatch},
+        compute::concat_batches,
+        datatypes::UInt64Type,
+    },
+    error::Result,
+    execution::SendableRecordBatchStream,
+    physical_plan::memory::MemoryStream,
+};
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use serde_json::json;
+
+pub(crate) const DEFAULT_PAGE_LIMIT: usize = 100;
+pub(crate) const MAX_PAGE_LIMIT: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// The position right after the last row of a page: rows are totally ordered by
+/// (slot, signature), so a page resumes exactly where the previous one stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Cursor {
+    slot: u64,
+    signature: String,
+}
+
+impl Cursor {
+    /// An opaque, url safe token
+    pub(crate) fn encode(&self) -> String {
+        format!("{}.{}", self.slot, self.signature)
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub(crate) fn decode(token: &str) -> eyre::Result<Self> {
+        let invalid = || eyre::eyre!("invalid cursor: {}", token);
+        if token.len() % 2 != 0 || !token.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&token[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        let text = String::from_utf8(bytes).map_err(|_| invalid())?;
+        let (slot, signature) = text.split_once('.').ok_or_else(invalid)?;
+        //NOTE the signature ends up in SQL, so only base58 is accepted
+        if !is_base58(signature) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            slot: slot.parse().map_err(|_| invalid())?,
+            signature: signature.to_string(),
+        })
+    }
+
+    /// The cursor after the last row of `batch`
+    pub(crate) fn after_last(batch: &RecordBatch) -> Option<Self> {
+        let last = batch.num_rows().checked_sub(1)?;
+        let slot = batch
+            .column_by_name("slot")?
+            .as_primitive_opt::<UInt64Type>()?
+            .value(last);
+        let signature = batch
+            .column_by_name("signature")?
+            .as_string_opt::<i32>()?
+            .value(last)
+            .to_string();
+        Some(Self { slot, signature })
+    }
+}
+
+pub(crate) fn is_base58(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() && !matches!(c, '0' | 'O' | 'I' | 'l'))
+}
+
+/// Paging query parameters shared by the list endpoints
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct PageParams {
+    pub(crate) limit: Option<usize>,
+    #[serde(default)]
+    pub(crate) order: Order,
+    pub(crate) cursor: Option<String>,
+}
+
+impl PageParams {
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .clamp(1, MAX_PAGE_LIMIT)
+    }
+
+    /// A query over `table` for one page of the rows matching `predicate`. One row more
+    /// than the limit is fetched, telling whether there is a next page.
+    pub(crate) fn to_sql(&self, table: &str, predicate: &str) -> eyre::Result<String> {
+        let (cmp, dir) = match self.order {
+            Order::Asc => (">", "ASC"),
+            Order::Desc => ("<", "DESC"),
+        };
+        let after = match self.cursor.as_deref().map(Cursor::decode).transpose()? {
+            Some(Cursor { slot, signature }) => format!(
+                " AND (slot {cmp} {slot} OR (slot = {slot} AND signature {cmp} '{signature}'))"
+            ),
+            None => String::new(),
+        };
+        Ok(format!(
+            "SELECT * FROM {table} WHERE ({predicate}){after} ORDER BY slot {dir}, signature {dir} LIMIT {}",
+            self.limit() + 1
+        ))
+    }
+}
+
+/// Filters of an account's transactions, every one set has to match
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AccountFilter {
+    /// unix seconds or RFC 3339, inclusive
+    pub(crate) from: Option<String>,
+    /// unix seconds or RFC 3339, exclusive
+    pub(crate) to: Option<String>,
+    pub(crate) success: Option<bool>,
+    pub(crate) epoch: Option<u64>,
+}
+
+impl AccountFilter {
+    /// The row filter of the transactions of `account`, the account lookup first so the
+    /// scan goes through the account index
+    pub(crate) fn predicate(&self, account: &str) -> eyre::Result<String> {
+        let mut predicate = format!("array_has(account_keys, '{}')", account);
+        if let Some(from) = self.from.as_deref() {
+            let from = parse_timestamp(from)?;
+            predicate += &format!(" AND block_time >= to_timestamp_seconds({})", from);
+        }
+        if let Some(to) = self.to.as_deref() {
+            let to = parse_timestamp(to)?;
+            predicate += &format!(" AND block_time < to_timestamp_seconds({})", to);
+        }
+        match self.success {
+            Some(true) => predicate += " AND err IS NULL",
+            Some(false) => predicate += " AND err IS NOT NULL",
+            None => {}
+        }
+        if let Some(epoch) = self.epoch {
+            predicate += &format!(" AND epoch = {}", epoch);
+        }
+        Ok(predicate)
+    }
+}
+
+/// Rows of `batches` as NDJSON, one chunk per batch, so nothing is collected in memory.
+/// With a `limit`, the stream is a page of a `PageParams::to_sql` query: at most `limit`
+/// rows, then a `{"next_cursor": ...}` line if the query found one more row.
+pub(crate) fn ndjson_stream(
+    batches: SendableRecordBatchStream,
+    limit: Option<usize>,
+) -> impl Stream<Item = Result<Bytes>> + Send {
+    let state = NdjsonState {
+        batches,
+        remaining: limit,
+        last: None,
+        more: false,
+    };
+    futures_util::stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+        if state.more {
+            return next_cursor_line(state.last);
+        }
+        loop {
+            let batch = match state.batches.next().await? {
+                Ok(batch) if batch.num_rows() == 0 => continue,
+                Ok(batch) => batch,
+                Err(e) => return Some((Err(e), None)),
+            };
+            let batch = match state.remaining {
+                //NOTE the page is full and a row is left, so there is a next page
+                Some(0) => return next_cursor_line(state.last),
+                Some(n) => {
+                    state.more = batch.num_rows() > n;
+                    let page = batch.slice(0, n.min(batch.num_rows()));
+                    state.remaining = Some(n - page.num_rows());
+                    state.last = Cursor::after_last(&page);
+                    page
+                }
+                None => batch,
+            };
+            return match batch_to_ndjson(&batch) {
+                Ok(chunk) => Some((Ok(chunk), Some(state))),
+                Err(e) => Some((Err(e), None)),
+            };
+        }
+    })
+}
+
+struct NdjsonState {
+    batches: SendableRecordBatchStream,
+    remaining: Option<usize>,
+    last: Option<Cursor>,
+    more: bool,
+}
+
+/// The first `limit` rows of a `PageParams::to_sql` query, with the cursor of the next page.
+///NOTE for encodings with no room for a trailing cursor line, at most one page is buffered
+pub(crate) async fn take_page(
+    batches: SendableRecordBatchStream,
+    limit: usize,
+) -> Result<(SendableRecordBatchStream, Option<String>)> {
+    let schema = batches.schema();
+    let batches: Vec<_> = batches.try_collect().await?;
+    let rows = concat_batches(&schema, &batches)?;
+    let (rows, next_cursor) = if rows.num_rows() > limit {
+        let page = rows.slice(0, limit);
+        let cursor = Cursor::after_last(&page).map(|c| c.encode());
+        (page, cursor)
+    } else {
+        (rows, None)
+    };
+    let page = MemoryStream::try_new(vec![rows], schema, None)?;
+    Ok((Box::pin(page), next_cursor))
+}
+
+fn next_cursor_line(last: Option<Cursor>) -> Option<(Result<Bytes>, Option<NdjsonState>)> {
+    let line = json!({ "next_cursor": last?.encode() }).to_string() + "\n";
+    Some((Ok(Bytes::from(line)), None))
+}
+
+fn batch_to_ndjson(batch: &RecordBatch) -> Result<Bytes> {
+    let mut writer = arrow_json::LineDelimitedWriter::new(Vec::new());
+    writer.write(batch)?;
+    writer.finish()?;
+    Ok(Bytes::from(writer.into_inner()))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor() -> eyre::Result<()> {
+        let cursor = Cursor {
+            slot: 311_516_666,
+            signature: "5VERv8NMvzbJMEkV8xnrLkEaWRtSz9CosKDYjCJjBRnbJLgp8uirBgmQpjKhoR4tjF3ZpRzrFmBV6UjKdiSZkQUW".to_string(),
+        };
+        assert_eq!(Cursor::decode(&cursor.encode())?, cursor);
+        assert!(Cursor::decode("zz").is_err());
+        let injected: String = "1.x' OR 1=1 --"
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert!(Cursor::decode(&injected).is_err());
+
+        let page = PageParams {
+            limit: Some(2),
+            order: Order::Desc,
+            cursor: Some(cursor.encode()),
+        };
+        let sql = page.to_sql("transactions", "fee > 0")?;
+        assert!(sql.contains("slot < 311516666 OR (slot = 311516666 AND signature < '5VER"));
+        assert!(sql.ends_with("ORDER BY slot DESC, signature DESC LIMIT 3"));
+        Ok(())
+    }
+}
@@ -0,0 +1,447 @@
+use async_trait::async_trait;
+use datafusion::{
+    arrow::{
+        array::{Array, ArrayRef, AsArray, RecordBatch},
+        datatypes::{
+            DataType, Float32Type, Float64Type, Int16Type, Int32Type, Int64Type, Int8Type, Schema,
+            TimeUnit, TimestampMicrosecondType, TimestampMillisecondType, TimestampNanosecondType,
+            TimestampSecondType, UInt16Type, UInt32Type, UInt64Type, UInt8Type,
+        },
+        util::display::{ArrayFormatter, FormatOptions},
+    },
+    sql::parser::DFParser,
+};
+use futures_util::{stream, Sink, StreamExt};
+use log::{debug, info};
+use pgwire::{
+    api::{
+        auth::noop::NoopStartupHandler,
+        copy::NoopCopyHandler,
+        portal::{Format, Portal},
+        query::{ExtendedQueryHandler, SimpleQueryHandler},
+        results::{
+            DataRowEncoder, DescribePortalResponse, DescribeStatementResponse, FieldInfo,
+            QueryResponse, Response, Tag,
+        },
+        stmt::{NoopQueryParser, StoredStatement},
+        ClientInfo, PgWireHandlerFactory, Type,
+    },
+    error::{ErrorInfo, PgWireError, PgWireResult},
+    messages::{data::DataRow, PgWireBackendMessage},
+    tokio::process_socket,
+};
+use std::{fmt::Debug, net::SocketAddr, sync::Arc};
+use tokio::net::TcpListener;
+
+use crate::stream::{query_schema, query_stream, TheadSafeStreamer};
+
+/// PostgreSQL wire protocol over the store, for psql, Grafana, Metabase and the like.
+/// Simple and extended queries are planned by DataFusion, read-only like every other
+/// query path: only `SELECT`s, `SHOW`s and `EXPLAIN`s run.
+///NOTE bind parameters are not supported, values have to be inlined in the SQL
+pub struct SolAggPg {
+    streamer: TheadSafeStreamer,
+    parser: Arc<NoopQueryParser>,
+}
+
+impl NoopStartupHandler for SolAggPg {}
+
+impl SolAggPg {
+    pub fn new(streamer: TheadSafeStreamer) -> Self {
+        Self {
+            streamer,
+            parser: Arc::new(NoopQueryParser::new()),
+        }
+    }
+
+    async fn fields(&self, sql: &str, format: &Format) -> PgWireResult<Vec<FieldInfo>> {
+        if session_command(sql).is_some() {
+            return Ok(vec![]);
+        }
+        let schema = query_schema(self.streamer.clone(), sql)
+            .await
+            .map_err(user_error)?;
+        Ok(fields(&schema, format))
+    }
+
+    async fn run<'a>(&self, sql: &str, format: &Format) -> PgWireResult<Response<'a>> {
+        if let Some(tag) = session_command(sql) {
+            return Ok(Response::Execution(Tag::new(tag)));
+        }
+        debug!("Pg query: {}", sql);
+        let batches = query_stream(self.streamer.clone(), sql)
+            .await
+            .map_err(user_error)?;
+        let schema = Arc::new(fields(&batches.schema(), format));
+        let rows_schema = schema.clone();
+        let rows = batches.flat_map(move |batch| {
+            let rows = match batch {
+                Ok(batch) => encode_rows(&batch, &rows_schema),
+                Err(e) => vec![Err(user_error(e))],
+            };
+            stream::iter(rows)
+        });
+        Ok(Response::Query(QueryResponse::new(schema, rows)))
+    }
+}
+
+#[async_trait]
+impl SimpleQueryHandler for SolAggPg {
+    async fn do_query<'a, C>(
+        &self,
+        _client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        //NOTE a simple query may hold several statements, each gets a response
+        let statements = DFParser::parse_sql(query).map_err(user_error)?;
+        if statements.is_empty() {
+            return Ok(vec![Response::EmptyQuery]);
+        }
+        let mut responses = Vec::with_capacity(statements.len());
+        for statement in statements {
+            responses.push(
+                self.run(&statement.to_string(), &Format::UnifiedText)
+                    .await?,
+            );
+        }
+        Ok(responses)
+    }
+}
+
+#[async_trait]
+impl ExtendedQueryHandler for SolAggPg {
+    type Statement = String;
+    type QueryParser = NoopQueryParser;
+
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.parser.clone()
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        _client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        _max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if portal.parameter_len() > 0 {
+            return Err(user_error("bind parameters are not supported"));
+        }
+        let sql = &portal.statement.statement;
+        if sql.trim().is_empty() {
+            return Ok(Response::EmptyQuery);
+        }
+        self.run(sql, &portal.result_column_format).await
+    }
+
+    async fn do_describe_statement<C>(
+        &self,
+        _client: &mut C,
+        target: &StoredStatement<Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + Unpin + Send + Sync,
+    {
+        let fields = self
+            .fields(&target.statement, &Format::UnifiedBinary)
+            .await?;
+        Ok(DescribeStatementResponse::new(vec![], fields))
+    }
+
+    async fn do_describe_portal<C>(
+        &self,
+        _client: &mut C,
+        target: &Portal<Self::Statement>,
+    ) -> PgWireResult<DescribePortalResponse>
+    where
+        C: ClientInfo + Unpin + Send + Sync,
+    {
+        let fields = self
+            .fields(&target.statement.statement, &target.result_column_format)
+            .await?;
+        Ok(DescribePortalResponse::new(fields))
+    }
+}
+
+struct SolAggPgFactory {
+    handler: Arc<SolAggPg>,
+}
+
+impl PgWireHandlerFactory for SolAggPgFactory {
+    type StartupHandler = SolAggPg;
+    type SimpleQueryHandler = SolAggPg;
+    type ExtendedQueryHandler = SolAggPg;
+    type CopyHandler = NoopCopyHandler;
+
+    fn simple_query_handler(&self) -> Arc<Self::SimpleQueryHandler> {
+        self.handler.clone()
+    }
+
+    fn extended_query_handler(&self) -> Arc<Self::ExtendedQueryHandler> {
+        self.handler.clone()
+    }
+
+    fn startup_handler(&self) -> Arc<Self::StartupHandler> {
+        self.handler.clone()
+    }
+
+    fn copy_handler(&self) -> Arc<Self::CopyHandler> {
+        Arc::new(NoopCopyHandler)
+    }
+}
+
+pub async fn pg_server(streamer: TheadSafeStreamer, port: u16) -> eyre::Result<()> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let factory = Arc::new(SolAggPgFactory {
+        handler: Arc::new(SolAggPg::new(streamer)),
+    });
+    let listener = TcpListener::bind(addr).await?;
+    info!(
+        "SolAgg PostgreSQL endpoint is starting at postgres://{} ...",
+        addr
+    );
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let factory = factory.clone();
+        tokio::spawn(async move {
+            if let Err(e) = process_socket(socket, None, factory).await {
+                debug!("Pg connection from {} closed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Session statements clients send on connect, e.g. `SET client_encoding`, acknowledged
+/// without effect as there is no transaction nor session state
+fn session_command(sql: &str) -> Option<&'static str> {
+    let keyword = sql.split_whitespace().next()?.to_ascii_uppercase();
+    match keyword.trim_end_matches(';') {
+        "SET" => Some("SET"),
+        "BEGIN" | "START" => Some("BEGIN"),
+        "COMMIT" | "END" => Some("COMMIT"),
+        "ROLLBACK" => Some("ROLLBACK"),
+        "DISCARD" => Some("DISCARD ALL"),
+        _ => None,
+    }
+}
+
+fn fields(schema: &Schema, format: &Format) -> Vec<FieldInfo> {
+    schema
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, f)| {
+            FieldInfo::new(
+                f.name().clone(),
+                None,
+                None,
+                pg_type(f.data_type()),
+                format.format_for(i),
+            )
+        })
+        .collect()
+}
+
+//NOTE unsigned integers widen to the next signed type, u64 values (slots, lamports)
+//     are well below i64::MAX; anything else is sent as text, e.g. lists as `[a, b]`
+fn pg_type(data_type: &DataType) -> Type {
+    match data_type {
+        DataType::Boolean => Type::BOOL,
+        DataType::Int8 | DataType::Int16 | DataType::UInt8 => Type::INT2,
+        DataType::Int32 | DataType::UInt16 => Type::INT4,
+        DataType::Int64 | DataType::UInt32 | DataType::UInt64 => Type::INT8,
+        DataType::Float32 => Type::FLOAT4,
+        DataType::Float64 => Type::FLOAT8,
+        DataType::Timestamp(_, None) => Type::TIMESTAMP,
+        DataType::Timestamp(_, Some(_)) => Type::TIMESTAMPTZ,
+        _ => Type::VARCHAR,
+    }
+}
+
+fn encode_rows(batch: &RecordBatch, schema: &Arc<Vec<FieldInfo>>) -> Vec<PgWireResult<DataRow>> {
+    let options = FormatOptions::default();
+    let formatters = match batch
+        .columns()
+        .iter()
+        .map(|c| ArrayFormatter::try_new(c.as_ref(), &options))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(formatters) => formatters,
+        Err(e) => return vec![Err(user_error(e))],
+    };
+    (0..batch.num_rows())
+        .map(|row| {
+            let mut encoder = DataRowEncoder::new(schema.clone());
+            for (column, formatter) in batch.columns().iter().zip(&formatters) {
+                encode_value(&mut encoder, column, formatter, row)?;
+            }
+            encoder.finish()
+        })
+        .collect()
+}
+
+fn encode_value(
+    encoder: &mut DataRowEncoder,
+    column: &ArrayRef,
+    formatter: &ArrayFormatter,
+    row: usize,
+) -> PgWireResult<()> {
+    if column.is_null(row) {
+        return encoder.encode_field(&None::<i32>);
+    }
+    match column.data_type() {
+        DataType::Boolean => encoder.encode_field(&column.as_boolean().value(row)),
+        DataType::Int8 => {
+            encoder.encode_field(&(column.as_primitive::<Int8Type>().value(row) as i16))
+        }
+        DataType::Int16 => encoder.encode_field(&column.as_primitive::<Int16Type>().value(row)),
+        DataType::UInt8 => {
+            encoder.encode_field(&(column.as_primitive::<UInt8Type>().value(row) as i16))
+        }
+        DataType::Int32 => encoder.encode_field(&column.as_primitive::<Int32Type>().value(row)),
+        DataType::UInt16 => {
+            encoder.encode_field(&(column.as_primitive::<UInt16Type>().value(row) as i32))
+        }
+        DataType::Int64 => encoder.encode_field(&column.as_primitive::<Int64Type>().value(row)),
+        DataType::UInt32 => {
+            encoder.encode_field(&(column.as_primitive::<UInt32Type>().value(row) as i64))
+        }
+        DataType::UInt64 => {
+            //NOTE INT8 is signed, values past i64::MAX have no PostgreSQL counterpart
+            let value = column.as_primitive::<UInt64Type>().value(row);
+            encoder.encode_field(&i64::try_from(value).map_err(user_error)?)
+        }
+        DataType::Timestamp(unit, tz) => {
+            let value = match unit {
+                TimeUnit::Second => column
+                    .as_primitive::<TimestampSecondType>()
+                    .value_as_datetime(row),
+                TimeUnit::Millisecond => column
+                    .as_primitive::<TimestampMillisecondType>()
+                    .value_as_datetime(row),
+                TimeUnit::Microsecond => column
+                    .as_primitive::<TimestampMicrosecondType>()
+                    .value_as_datetime(row),
+                TimeUnit::Nanosecond => column
+                    .as_primitive::<TimestampNanosecondType>()
+                    .value_as_datetime(row),
+            };
+            let Some(value) = value else {
+                return Err(user_error(format!(
+                    "timestamp out of range: {}",
+                    formatter.value(row)
+                )));
+            };
+            //NOTE zoned timestamps are instants, sent in UTC
+            match tz {
+                None => encoder.encode_field(&value),
+                Some(_) => encoder.encode_field(&value.and_utc()),
+            }
+        }
+        DataType::Float32 => encoder.encode_field(&column.as_primitive::<Float32Type>().value(row)),
+        DataType::Float64 => encoder.encode_field(&column.as_primitive::<Float64Type>().value(row)),
+        _ => encoder.encode_field(&formatter.value(row).to_string()),
+    }
+}
+
+fn user_error(e: impl std::fmt::Display) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_string(),
+        "42000".to_string(),
+        e.to_string(),
+    )))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::{
+        store::{StoreConfig, TransactionStore},
+        stream::SolAggStreamer,
+    };
+
+    fn service() -> SolAggPg {
+        let tx_store = TransactionStore::mock(StoreConfig::default());
+        SolAggPg::new(SolAggStreamer::with_store(tx_store))
+    }
+
+    #[tokio::test]
+    async fn test_run() -> eyre::Result<()> {
+        let pg = service();
+        let Response::Query(result) = pg
+            .run(
+                "SELECT count(1) AS n, 'x' AS s, to_timestamp_seconds(0) AS t FROM transactions",
+                &Format::UnifiedText,
+            )
+            .await?
+        else {
+            eyre::bail!("not a query response");
+        };
+        let types: Vec<_> = result
+            .row_schema()
+            .iter()
+            .map(|f| f.datatype().clone())
+            .collect();
+        assert_eq!(types, vec![Type::INT8, Type::VARCHAR, Type::TIMESTAMP]);
+        assert_eq!(
+            pg_type(&DataType::Timestamp(TimeUnit::Second, Some("UTC".into()))),
+            Type::TIMESTAMPTZ
+        );
+
+        let batch = crate::types::to_record_batch(
+            &[
+                serde_json::json!({"slot": 7, "keys": ["a", "b"]}),
+                serde_json::json!({"slot": 8}),
+            ],
+            Schema::new(vec![
+                datafusion::arrow::datatypes::Field::new("slot", DataType::UInt64, false),
+                datafusion::arrow::datatypes::Field::new_list(
+                    "keys",
+                    datafusion::arrow::datatypes::Field::new("item", DataType::Utf8, true),
+                    true,
+                ),
+            ]),
+        )?;
+        let schema = Arc::new(fields(&batch.schema(), &Format::UnifiedText));
+        let rows = encode_rows(&batch, &schema)
+            .into_iter()
+            .collect::<PgWireResult<Vec<_>>>()?;
+        assert_eq!(rows.len(), 2);
+        //u64 values past i64::MAX are an error, not a wrapped negative number
+        let batch = crate::types::to_record_batch(
+            &[serde_json::json!({"slot": u64::MAX})],
+            Schema::new(vec![datafusion::arrow::datatypes::Field::new(
+                "slot",
+                DataType::UInt64,
+                false,
+            )]),
+        )?;
+        let schema = Arc::new(fields(&batch.schema(), &Format::UnifiedText));
+        assert!(encode_rows(&batch, &schema)[0].is_err());
+
+        assert!(matches!(
+            pg.run("SET extra_float_digits = 3", &Format::UnifiedText)
+                .await?,
+            Response::Execution(_)
+        ));
+        //read-only, the shared context is never changed
+        assert!(pg
+            .run("CREATE TABLE t AS SELECT 1", &Format::UnifiedText)
+            .await
+            .is_err());
+        assert!(pg
+            .run("DROP TABLE transactions", &Format::UnifiedText)
+            .await
+            .is_err());
+        Ok(())
+    }
+}
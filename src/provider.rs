@@ -0,0 +1,380 @@
+use crate::{
+    index::RowPos,
+    segment::{Segment, SegmentSet},
+};
+use async_trait::async_trait;
+use datafusion::{
+    arrow::{array::RecordBatch, compute::concat_batches, datatypes::SchemaRef},
+    common::{stats::Precision, Column, Statistics},
+    datasource::{TableProvider, TableType},
+    error::Result,
+    execution::context::SessionState,
+    logical_expr::{
+        expr::{Between, BinaryExpr, ScalarFunction},
+        Expr, Operator, TableProviderFilterPushDown,
+    },
+    physical_plan::{memory::MemoryExec, ExecutionPlan},
+    scalar::ScalarValue,
+};
+use std::{
+    any::Any,
+    sync::{Arc, RwLock},
+};
+
+/// Which batches of a segment a `StoreTable` exposes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TableKind {
+    Transactions,
+    Votes,
+    Blocks,
+    Rewards,
+}
+
+impl TableKind {
+    fn batch(self, segment: &Segment) -> Option<&RecordBatch> {
+        match self {
+            TableKind::Transactions => Some(&segment.transactions),
+            TableKind::Votes => segment.votes.as_ref(),
+            TableKind::Blocks => Some(&segment.blocks),
+            TableKind::Rewards => Some(&segment.rewards),
+        }
+    }
+}
+
+/// A live view over the store's segments, registered once in a long-lived `SessionContext`.
+/// Filters on `slot`, `epoch` and `block_time` prune whole segments before the scan, and equality
+/// filters on `signature` or an account turn a `transactions` scan into an index lookup.
+pub(crate) struct StoreTable {
+    kind: TableKind,
+    schema: SchemaRef,
+    data: Arc<RwLock<SegmentSet>>,
+}
+
+impl StoreTable {
+    pub(crate) fn new(kind: TableKind, schema: SchemaRef, data: Arc<RwLock<SegmentSet>>) -> Self {
+        Self { kind, schema, data }
+    }
+}
+
+#[async_trait]
+impl TableProvider for StoreTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    async fn scan(
+        &self,
+        state: &SessionState,
+        projection: Option<&Vec<usize>>,
+        filters: &[Expr],
+        _limit: Option<usize>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        let mut bounds = ScanBounds::default();
+        for f in filters {
+            bounds.narrow(f);
+        }
+        let lookup = match self.kind {
+            TableKind::Transactions => filters.iter().find_map(KeyLookup::from_expr),
+            _ => None,
+        };
+        let batches: Vec<RecordBatch> = {
+            let data = self.data.read().unwrap_or_else(|e| e.into_inner());
+            match lookup {
+                Some(lookup) => {
+                    let in_bounds = |p: &RowPos| (bounds.slot.0..=bounds.slot.1).contains(&p.0);
+                    let positions: Vec<RowPos> = match &lookup {
+                        KeyLookup::Signature(sig) => {
+                            data.index.signature(sig).into_iter().collect()
+                        }
+                        KeyLookup::Account(key) => data.index.account(key),
+                    };
+                    let positions: Vec<RowPos> = positions.into_iter().filter(in_bounds).collect();
+                    //NOTE one batch rather than a single-row batch per hit
+                    let rows = data.rows(&positions);
+                    match rows.first() {
+                        Some(b) => vec![concat_batches(&b.schema(), &rows)?],
+                        None => vec![],
+                    }
+                }
+                None => data
+                    .in_range(bounds.slot.0, bounds.slot.1)
+                    .filter(|s| bounds.may_contain_epoch(s) && bounds.may_contain_time(s))
+                    .filter_map(|s| self.kind.batch(s).cloned())
+                    .collect(),
+            }
+        };
+        //NOTE spread the segments over the partitions for a parallel scan
+        let n = state.config().target_partitions().max(1);
+        let mut partitions = vec![vec![]; n.min(batches.len()).max(1)];
+        let len = partitions.len();
+        for (i, b) in batches.into_iter().enumerate() {
+            partitions[i % len].push(b);
+        }
+        let exec = MemoryExec::try_new(&partitions, self.schema(), projection.cloned())?;
+        Ok(Arc::new(exec))
+    }
+
+    fn supports_filters_pushdown(
+        &self,
+        filters: &[&Expr],
+    ) -> Result<Vec<TableProviderFilterPushDown>> {
+        //NOTE pruning is per segment and an account lookup returns every row having the
+        //     account anywhere, so the filters are still applied to the rows
+        Ok(filters
+            .iter()
+            .map(|f| {
+                let lookup =
+                    self.kind == TableKind::Transactions && KeyLookup::from_expr(f).is_some();
+                if lookup || ScanBounds::default().narrow(f) {
+                    TableProviderFilterPushDown::Inexact
+                } else {
+                    TableProviderFilterPushDown::Unsupported
+                }
+            })
+            .collect())
+    }
+
+    fn statistics(&self) -> Option<Statistics> {
+        let data = self.data.read().unwrap_or_else(|e| e.into_inner());
+        let (rows, bytes) = data
+            .in_range(0, u64::MAX)
+            .filter_map(|s| self.kind.batch(s))
+            .fold((0, 0), |(rows, bytes), b| {
+                (rows + b.num_rows(), bytes + b.get_array_memory_size())
+            });
+        let mut stats = Statistics::new_unknown(&self.schema);
+        //NOTE pending confirmed slots are counted, they may still be rolled back
+        stats.num_rows = Precision::Inexact(rows);
+        stats.total_byte_size = Precision::Inexact(bytes);
+        Some(stats)
+    }
+}
+
+/// An equality filter answered by the store's transaction indexes
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum KeyLookup {
+    Signature(String),
+    Account(String),
+}
+
+impl KeyLookup {
+    /// `signature = 'x'`, `sender = 'x'`, `receiver = 'x'` or `array_has(account_keys, 'x')`
+    fn from_expr(expr: &Expr) -> Option<Self> {
+        match expr {
+            Expr::BinaryExpr(BinaryExpr {
+                left,
+                op: Operator::Eq,
+                right,
+            }) => {
+                let (c, v) = match (left.as_ref(), right.as_ref()) {
+                    (Expr::Column(c), Expr::Literal(v)) | (Expr::Literal(v), Expr::Column(c)) => {
+                        (c, scalar_str(v)?)
+                    }
+                    _ => return None,
+                };
+                match c.name.as_str() {
+                    "signature" => Some(KeyLookup::Signature(v)),
+                    "sender" | "receiver" => Some(KeyLookup::Account(v)),
+                    _ => None,
+                }
+            }
+            Expr::ScalarFunction(ScalarFunction { func, args }) if func.name() == "array_has" => {
+                match args.as_slice() {
+                    [Expr::Column(c), Expr::Literal(v)] if c.name == "account_keys" => {
+                        Some(KeyLookup::Account(scalar_str(v)?))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+fn scalar_str(v: &ScalarValue) -> Option<String> {
+    match v {
+        ScalarValue::Utf8(Some(v)) | ScalarValue::LargeUtf8(Some(v)) => Some(v.clone()),
+        //NOTE literals compared to the dictionary encoded pubkey columns get coerced
+        ScalarValue::Dictionary(_, v) => scalar_str(v),
+        _ => None,
+    }
+}
+
+/// Inclusive slot, epoch and `block_time` bounds implied by the pushed down filters
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ScanBounds {
+    slot: (u64, u64),
+    epoch: (u64, u64),
+    time: (i64, i64),
+}
+
+impl Default for ScanBounds {
+    fn default() -> Self {
+        Self {
+            slot: (0, u64::MAX),
+            epoch: (0, u64::MAX),
+            time: (i64::MIN, i64::MAX),
+        }
+    }
+}
+
+impl ScanBounds {
+    fn may_contain_epoch(&self, segment: &Segment) -> bool {
+        (self.epoch.0..=self.epoch.1).contains(&segment.epoch)
+    }
+
+    fn may_contain_time(&self, segment: &Segment) -> bool {
+        if self.time == (i64::MIN, i64::MAX) {
+            return true;
+        }
+        //NOTE a segment without any known block_time can not be pruned
+        segment
+            .block_time_range()
+            .map_or(true, |(lo, hi)| lo <= self.time.1 && self.time.0 <= hi)
+    }
+
+    /// Narrows the bounds by a filter, returns false if the filter is not understood
+    fn narrow(&mut self, expr: &Expr) -> bool {
+        match expr {
+            Expr::BinaryExpr(BinaryExpr { left, op, right }) => {
+                match (left.as_ref(), right.as_ref()) {
+                    (Expr::Column(c), Expr::Literal(v)) => self.narrow_op(c, *op, v),
+                    (Expr::Literal(v), Expr::Column(c)) => match op.swap() {
+                        Some(op) => self.narrow_op(c, op, v),
+                        None => false,
+                    },
+                    _ => false,
+                }
+            }
+            Expr::Between(Between {
+                expr,
+                negated: false,
+                low,
+                high,
+            }) => match (expr.as_ref(), low.as_ref(), high.as_ref()) {
+                (Expr::Column(c), Expr::Literal(lo), Expr::Literal(hi)) => {
+                    let mut b = *self;
+                    if b.narrow_op(c, Operator::GtEq, lo) && b.narrow_op(c, Operator::LtEq, hi) {
+                        *self = b;
+                        true
+                    } else {
+                        false
+                    }
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn narrow_op(&mut self, col: &Column, op: Operator, v: &ScalarValue) -> bool {
+        match col.name.as_str() {
+            "slot" => match scalar_i128(v) {
+                Some(v) => narrow_range(&mut self.slot, op, v),
+                None => false,
+            },
+            "epoch" => match scalar_i128(v) {
+                Some(v) => narrow_range(&mut self.epoch, op, v),
+                None => false,
+            },
+            "block_time" => match v {
+                ScalarValue::TimestampSecond(Some(v), _) => {
+                    narrow_range(&mut self.time, op, *v as i128)
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+fn scalar_i128(v: &ScalarValue) -> Option<i128> {
+    match v {
+        ScalarValue::UInt64(Some(v)) => Some(*v as i128),
+        ScalarValue::UInt32(Some(v)) => Some(*v as i128),
+        ScalarValue::Int64(Some(v)) => Some(*v as i128),
+        ScalarValue::Int32(Some(v)) => Some(*v as i128),
+        _ => None,
+    }
+}
+
+fn narrow_range<T>(range: &mut (T, T), op: Operator, v: i128) -> bool
+where
+    T: Copy + Ord + TryFrom<i128>,
+{
+    //NOTE a literal out of the column type's range is left to the row filter
+    let convert = |v: i128| T::try_from(v).ok();
+    let (lo, hi) = range;
+    match op {
+        Operator::Eq => {
+            let Some(v) = convert(v) else { return false };
+            *lo = (*lo).max(v);
+            *hi = (*hi).min(v);
+        }
+        Operator::Gt | Operator::GtEq => {
+            let v = if op == Operator::Gt { v + 1 } else { v };
+            let Some(v) = convert(v) else { return false };
+            *lo = (*lo).max(v);
+        }
+        Operator::Lt | Operator::LtEq => {
+            let v = if op == Operator::Lt { v - 1 } else { v };
+            let Some(v) = convert(v) else { return false };
+            *hi = (*hi).min(v);
+        }
+        _ => return false,
+    }
+    true
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use datafusion::prelude::{col, lit};
+
+    #[test]
+    fn test_scan_bounds() {
+        let mut b = ScanBounds::default();
+        assert!(b.narrow(&col("slot").gt_eq(lit(100u64))));
+        assert!(b.narrow(&col("slot").lt(lit(200i64))));
+        assert!(b.narrow(&lit(150u64).gt(col("slot"))));
+        assert_eq!(b.slot, (100, 149));
+
+        assert!(b.narrow(&col("block_time").between(
+            lit(ScalarValue::TimestampSecond(Some(10), None)),
+            lit(ScalarValue::TimestampSecond(Some(20), None)),
+        )));
+        assert_eq!(b.time, (10, 20));
+
+        assert!(b.narrow(&col("epoch").eq(lit(700u64))));
+        assert_eq!(b.epoch, (700, 700));
+
+        assert!(!b.narrow(&col("fee").gt(lit(5000u64))));
+        assert!(!b.narrow(&col("slot").not_eq(lit(1u64))));
+        assert_eq!(b.slot, (100, 149));
+    }
+
+    #[test]
+    fn test_key_lookup() {
+        assert_eq!(
+            KeyLookup::from_expr(&col("signature").eq(lit("sig"))),
+            Some(KeyLookup::Signature("sig".to_string()))
+        );
+        assert_eq!(
+            KeyLookup::from_expr(&lit("a").eq(col("sender"))),
+            Some(KeyLookup::Account("a".to_string()))
+        );
+        assert_eq!(KeyLookup::from_expr(&col("fee").eq(lit("a"))), None);
+        assert_eq!(
+            KeyLookup::from_expr(&col("signature").not_eq(lit("sig"))),
+            None
+        );
+    }
+}
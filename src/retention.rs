@@ -0,0 +1,161 @@
+use crate::segment::{Segment, SegmentSet};
+use datafusion::{arrow::array::RecordBatch, parquet::arrow::ArrowWriter};
+use log::debug;
+use serde::Serialize;
+use solana_sdk::epoch_schedule::EpochSchedule;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// How much finalized data the store keeps in memory. Every limit set is enforced,
+/// the oldest slots are evicted first; pending slots are never evicted.
+#[derive(Debug, Clone)]
+pub struct Retention {
+    /// number of epochs to keep, the current one included
+    pub epochs: Option<usize>,
+    /// number of slots to keep, counted back from the current slot
+    pub slots: Option<u64>,
+    /// drop slots whose block time is older than this
+    pub max_age: Option<Duration>,
+    /// budget for the Arrow buffers of the stored batches, in bytes
+    pub max_bytes: Option<usize>,
+    /// write evicted segments as Parquet files under this directory instead of deleting them
+    pub evict_to: Option<PathBuf>,
+}
+
+impl Default for Retention {
+    //NOTE the previous epoch stays queryable past the boundary
+    fn default() -> Self {
+        Self {
+            epochs: Some(2),
+            slots: None,
+            max_age: None,
+            max_bytes: None,
+            evict_to: None,
+        }
+    }
+}
+
+impl Retention {
+    /// The first slot to keep, every finalized slot below it is to be evicted
+    pub(crate) fn cutoff(
+        &self,
+        data: &SegmentSet,
+        current_slot: u64,
+        current_epoch: u64,
+        schedule: &EpochSchedule,
+        now: i64,
+    ) -> Option<u64> {
+        let by_epochs = self
+            .epochs
+            .filter(|n| *n > 0)
+            .map(|n| schedule.get_first_slot_in_epoch(current_epoch.saturating_sub(n as u64 - 1)));
+        let by_slots = self
+            .slots
+            .filter(|n| *n > 0)
+            .map(|n| current_slot.saturating_sub(n - 1));
+        let by_age = self
+            .max_age
+            .and_then(|age| data.last_slot_before(now - age.as_secs() as i64))
+            .map(|s| s + 1);
+        let by_bytes = self.max_bytes.and_then(|budget| bytes_cutoff(data, budget));
+        [by_epochs, by_slots, by_age, by_bytes]
+            .into_iter()
+            .flatten()
+            .max()
+    }
+}
+
+/// The first slot to keep so that the stored bytes fit in `budget`
+fn bytes_cutoff(data: &SegmentSet, budget: usize) -> Option<u64> {
+    let mut total = data.bytes();
+    if total <= budget {
+        return None;
+    }
+    let mut finalized: Vec<&Segment> = data.segments.iter().chain(data.recent.values()).collect();
+    finalized.sort_by_key(|s| s.first_slot);
+    let mut cutoff = None;
+    for s in finalized {
+        if total <= budget {
+            break;
+        }
+        total = total.saturating_sub(s.memory_size());
+        cutoff = Some(s.last_slot + 1);
+    }
+    cutoff
+}
+
+/// Writes the batches of evicted segments as `<dir>/<table>/<first>-<last>.parquet`
+pub(crate) fn write_parquet(dir: &Path, segment: &Segment) -> eyre::Result<()> {
+    let mut tables: Vec<(&str, &RecordBatch)> = vec![
+        ("transactions", &segment.transactions),
+        ("blocks", &segment.blocks),
+        ("rewards", &segment.rewards),
+    ];
+    if let Some(votes) = &segment.votes {
+        tables.push(("vote_transactions", votes));
+    }
+    for (table, batch) in tables {
+        if batch.num_rows() == 0 {
+            continue;
+        }
+        let dir = dir.join(table);
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!(
+            "{}-{}.parquet",
+            segment.first_slot, segment.last_slot
+        ));
+        debug!("--- evict {} rows to {}", batch.num_rows(), path.display());
+        let mut writer = ArrowWriter::try_new(File::create(path)?, batch.schema(), None)?;
+        writer.write(batch)?;
+        writer.close()?;
+    }
+    Ok(())
+}
+
+/// Memory held by the stored Arrow buffers
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryReport {
+    pub segments: usize,
+    pub recent_slots: usize,
+    pub pending_slots: usize,
+    pub transaction_rows: usize,
+    pub indexed_signatures: usize,
+    pub epochs: Vec<u64>,
+    pub transactions_bytes: usize,
+    pub votes_bytes: usize,
+    pub blocks_bytes: usize,
+    pub rewards_bytes: usize,
+    /// bytes of the pending slots, already counted by the table totals above
+    pub pending_bytes: usize,
+    pub total_bytes: usize,
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_retention_cutoff() {
+        let data = SegmentSet::default();
+        let schedule = EpochSchedule::custom(40, 40, false);
+        let retention = Retention::default();
+        assert_eq!(retention.cutoff(&data, 30, 0, &schedule, 0), Some(0));
+        assert_eq!(retention.cutoff(&data, 100, 2, &schedule, 0), Some(40));
+
+        let retention = Retention {
+            epochs: Some(2),
+            slots: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(retention.cutoff(&data, 100, 2, &schedule, 0), Some(91));
+        assert_eq!(retention.cutoff(&data, 125, 3, &schedule, 0), Some(116));
+        let retention = Retention {
+            epochs: Some(1),
+            ..retention
+        };
+        assert_eq!(retention.cutoff(&data, 125, 3, &schedule, 0), Some(120));
+    }
+}
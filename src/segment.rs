@@ -0,0 +1,764 @@
+use crate::{
+    compact::{encode_pubkeys, sort_by_slot, TARGET_SEGMENT_ROWS},
+    index::{RowPos, TxIndex},
+    retention::MemoryReport,
+    types::Commitment,
+};
+use datafusion::arrow::{
+    array::{Array, ArrayRef, AsArray, BooleanArray, RecordBatch, StringArray, UInt64Array},
+    compute::{
+        concat_batches, filter_record_batch,
+        kernels::cmp::{gt_eq, lt, neq},
+        max, min,
+    },
+    datatypes::{TimestampSecondType, UInt64Type},
+    error::ArrowError,
+};
+use log::{debug, trace};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+    sync::Arc,
+};
+
+/// Recent slots are compacted into a segment at this count
+pub(crate) const SEGMENT_SLOTS: usize = 512;
+/// Ingest coalesces inline only when the background compactor falls this far behind
+const MAX_RECENT_SLOTS: usize = 4 * SEGMENT_SLOTS;
+
+/// All stored segments. Finalized data lives in coalesced segments sorted by first slot,
+/// plus the recent single-slot segments not coalesced yet.
+#[derive(Debug, Default)]
+pub(crate) struct SegmentSet {
+    pub(crate) segments: Vec<Segment>,
+    pub(crate) recent: BTreeMap<u64, Segment>,
+    //NOTE confirmed slots waiting for a root to prove them finalized or orphaned,
+    //     indexed by slot so a fork can be dropped or a slot replaced atomically
+    pub(crate) pending: BTreeMap<u64, Segment>,
+    //NOTE covers the transactions of all of the above, kept in sync by every mutation
+    pub(crate) index: TxIndex,
+    //NOTE bumped by every change to the finalized segments, so a compaction built
+    //     outside the lock can tell whether its inputs are still current
+    version: u64,
+    //NOTE Arrow buffer bytes of all of the above, kept in sync by every mutation so the
+    //     memory budget is checked without walking the segments
+    bytes: usize,
+}
+
+/// Segments a compaction round merges, snapshotted from a `SegmentSet` at `version`
+pub(crate) struct CompactionPlan {
+    version: u64,
+    target: CompactionTarget,
+    inputs: Vec<Segment>,
+}
+
+#[derive(Debug, Clone)]
+enum CompactionTarget {
+    Recent(Vec<u64>),
+    Segments(Range<usize>),
+}
+
+/// A merged segment ready to replace the inputs of its plan
+pub(crate) struct Compacted {
+    version: u64,
+    target: CompactionTarget,
+    segment: Segment,
+}
+
+impl CompactionPlan {
+    pub(crate) fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub(crate) fn build(self) -> eyre::Result<Compacted> {
+        Ok(Compacted {
+            version: self.version,
+            target: self.target,
+            segment: Segment::compact(&self.inputs)?,
+        })
+    }
+}
+
+impl SegmentSet {
+    pub(crate) fn clear(&mut self) {
+        self.segments.clear();
+        self.recent.clear();
+        self.pending.clear();
+        self.index.clear();
+        self.version += 1;
+        self.bytes = 0;
+    }
+
+    /// Arrow buffer bytes of the stored segments, pending ones included
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Whether the rows of a finalized `slot` are already stored
+    pub(crate) fn has_slot(&self, slot: u64) -> bool {
+        self.recent.contains_key(&slot)
+            || self
+                .segments
+                .iter()
+                .any(|s| s.contains(slot) && s.has_block(slot))
+    }
+
+    /// Stores a finalized slot, replacing any rows already stored for it
+    pub(crate) fn push(&mut self, slot: u64, segment: Segment) -> eyre::Result<()> {
+        //NOTE a new slot leaves the stored segments as they are, merges being built
+        //     stay valid; only replacing a stored slot invalidates them
+        let mut replaced = false;
+        if let Some(old) = self.recent.remove(&slot) {
+            debug!("--- replaced slot: {}", slot);
+            self.index.remove(slot, &old.transactions)?;
+            self.bytes -= old.memory_size();
+            replaced = true;
+        }
+        //NOTE the slot may also have been coalesced already
+        for s in self.segments.iter_mut() {
+            if s.contains(slot) && s.has_block(slot) {
+                debug!("--- replaced coalesced slot: {}", slot);
+                if let Some(old) = s.slot_transactions(slot) {
+                    self.index.remove(slot, &old)?;
+                }
+                let kept = s.without_slot(slot)?;
+                self.bytes = self.bytes - s.memory_size() + kept.memory_size();
+                *s = kept;
+                replaced = true;
+            }
+        }
+        self.index.insert(slot, &segment.transactions)?;
+        self.bytes += segment.memory_size();
+        self.recent.insert(slot, segment);
+        if replaced {
+            self.version += 1;
+        }
+        if self.recent.len() >= MAX_RECENT_SLOTS {
+            self.coalesce()?;
+        }
+        Ok(())
+    }
+
+    /// Stores a confirmed slot until it is finalized or orphaned, replacing any pending rows for it
+    pub(crate) fn insert_pending(&mut self, slot: u64, segment: Segment) -> eyre::Result<()> {
+        if let Some(old) = self.pending.remove(&slot) {
+            debug!("--- replaced pending slot: {}", slot);
+            self.index.remove(slot, &old.transactions)?;
+            self.bytes -= old.memory_size();
+        }
+        self.index.insert(slot, &segment.transactions)?;
+        self.bytes += segment.memory_size();
+        self.pending.insert(slot, segment);
+        Ok(())
+    }
+
+    pub(crate) fn remove_pending(&mut self, slot: u64) -> eyre::Result<Option<Segment>> {
+        let Some(segment) = self.pending.remove(&slot) else {
+            return Ok(None);
+        };
+        self.index.remove(slot, &segment.transactions)?;
+        self.bytes -= segment.memory_size();
+        Ok(Some(segment))
+    }
+
+    /// The transaction rows at the given index positions, one single-row batch per position
+    pub(crate) fn rows(&self, positions: &[RowPos]) -> Vec<RecordBatch> {
+        positions
+            .iter()
+            .filter_map(|(slot, row)| {
+                let segment = self
+                    .pending
+                    .get(slot)
+                    .or_else(|| self.recent.get(slot))
+                    .or_else(|| {
+                        self.segments
+                            .iter()
+                            .find(|s| s.contains(*slot) && s.tx_rows.contains_key(slot))
+                    })?;
+                let range = segment.tx_rows.get(slot)?;
+                let offset = range.start + *row as usize;
+                range
+                    .contains(&offset)
+                    .then(|| segment.transactions.slice(offset, 1))
+            })
+            .collect()
+    }
+
+    /// Merges all recent single-slot segments into one segment per epoch, sorted by slot
+    pub(crate) fn coalesce(&mut self) -> eyre::Result<()> {
+        let recent = std::mem::take(&mut self.recent);
+        if recent.is_empty() {
+            return Ok(());
+        }
+        self.version += 1;
+        let mut epochs: BTreeMap<u64, Vec<Segment>> = BTreeMap::new();
+        for segment in recent.into_values() {
+            self.bytes -= segment.memory_size();
+            epochs.entry(segment.epoch).or_default().push(segment);
+        }
+        for recent in epochs.into_values() {
+            let segment = Segment::compact(&recent)?;
+            trace!(
+                "coalesced slots {}..={} into one segment",
+                segment.first_slot,
+                segment.last_slot
+            );
+            self.bytes += segment.memory_size();
+            let idx = self
+                .segments
+                .partition_point(|s| s.first_slot <= segment.first_slot);
+            self.segments.insert(idx, segment);
+        }
+        Ok(())
+    }
+
+    /// The next merge to run: the recent slots of the oldest epoch once there are enough
+    /// of them or the epoch is over, otherwise a run of adjacent small segments of one
+    /// epoch which fits in one target sized segment.
+    ///NOTE segments never span epochs, so every epoch is a separate set of partitions
+    pub(crate) fn compaction_plan(&self) -> Option<CompactionPlan> {
+        if let Some(epoch) = self.recent.values().map(|s| s.epoch).min() {
+            let (recent, newer): (Vec<&Segment>, Vec<&Segment>) =
+                self.recent.values().partition(|s| s.epoch == epoch);
+            if recent.len() >= SEGMENT_SLOTS || !newer.is_empty() {
+                return Some(CompactionPlan {
+                    version: self.version,
+                    target: CompactionTarget::Recent(recent.iter().map(|s| s.first_slot).collect()),
+                    inputs: recent.into_iter().cloned().collect(),
+                });
+            }
+        }
+        let small = |s: &Segment| s.transactions.num_rows() < TARGET_SEGMENT_ROWS / 2;
+        let mut i = 0;
+        while i < self.segments.len() {
+            let (mut j, mut rows) = (i, 0);
+            while j < self.segments.len()
+                && small(&self.segments[j])
+                && self.segments[j].epoch == self.segments[i].epoch
+                && rows + self.segments[j].transactions.num_rows() <= TARGET_SEGMENT_ROWS
+            {
+                rows += self.segments[j].transactions.num_rows();
+                j += 1;
+            }
+            if j - i >= 2 {
+                return Some(CompactionPlan {
+                    version: self.version,
+                    target: CompactionTarget::Segments(i..j),
+                    inputs: self.segments[i..j].to_vec(),
+                });
+            }
+            i = j.max(i + 1);
+        }
+        None
+    }
+
+    /// Swaps a merged segment in for its inputs, returns false if the set changed
+    /// since the plan was made, in which case nothing is applied
+    pub(crate) fn apply_compaction(&mut self, compacted: Compacted) -> bool {
+        if compacted.version != self.version {
+            return false;
+        }
+        let segment = compacted.segment;
+        self.bytes += segment.memory_size();
+        trace!(
+            "compacted slots {}..={} into one segment",
+            segment.first_slot,
+            segment.last_slot
+        );
+        match compacted.target {
+            CompactionTarget::Recent(slots) => {
+                for slot in slots {
+                    if let Some(s) = self.recent.remove(&slot) {
+                        self.bytes -= s.memory_size();
+                    }
+                }
+                let idx = self
+                    .segments
+                    .partition_point(|s| s.first_slot <= segment.first_slot);
+                self.segments.insert(idx, segment);
+            }
+            //NOTE the merged segment starts at the first input, so the order holds
+            CompactionTarget::Segments(range) => {
+                for s in self.segments.splice(range, [segment]) {
+                    self.bytes -= s.memory_size();
+                }
+            }
+        }
+        self.version += 1;
+        true
+    }
+
+    /// Segments overlapping the slot range `[first_slot, last_slot]`, pending ones included;
+    /// every other segment can be pruned from a slot-range scan
+    pub(crate) fn in_range(
+        &self,
+        first_slot: u64,
+        last_slot: u64,
+    ) -> impl Iterator<Item = &Segment> {
+        self.segments
+            .iter()
+            .chain(self.recent.values())
+            .chain(self.pending.values())
+            .filter(move |s| s.overlaps(first_slot, last_slot))
+    }
+
+    /// The last finalized slot whose block time is before `ts`
+    pub(crate) fn last_slot_before(&self, ts: i64) -> Option<u64> {
+        self.segments
+            .iter()
+            .chain(self.recent.values())
+            .filter_map(|s| {
+                let (lo, hi) = s.block_time_range()?;
+                if lo >= ts {
+                    None
+                } else if hi < ts {
+                    Some(s.last_slot)
+                } else {
+                    s.last_slot_before(ts)
+                }
+            })
+            .max()
+    }
+
+    /// A copy of the finalized rows of every slot below `cutoff`, as `evict_before`
+    /// would remove them
+    pub(crate) fn before(&self, cutoff: u64) -> eyre::Result<Vec<Segment>> {
+        let mut below = vec![];
+        for segment in self.segments.iter().chain(self.recent.values()) {
+            if segment.last_slot < cutoff {
+                below.push(segment.clone());
+            } else if segment.first_slot < cutoff {
+                below.push(segment.split_at(cutoff)?.0);
+            }
+        }
+        Ok(below)
+    }
+
+    /// Removes the finalized rows of every slot below `cutoff` and returns them,
+    /// segments straddling the cutoff are split
+    pub(crate) fn evict_before(&mut self, cutoff: u64) -> eyre::Result<Vec<Segment>> {
+        let mut evicted = vec![];
+        let kept = self.recent.split_off(&cutoff);
+        for (slot, segment) in std::mem::replace(&mut self.recent, kept) {
+            self.index.remove(slot, &segment.transactions)?;
+            self.bytes -= segment.memory_size();
+            evicted.push(segment);
+        }
+        let mut kept = vec![];
+        for segment in std::mem::take(&mut self.segments) {
+            if segment.first_slot >= cutoff {
+                kept.push(segment);
+                continue;
+            }
+            for slot in segment.tx_rows.range(..cutoff).map(|(s, _)| *s) {
+                if let Some(rows) = segment.slot_transactions(slot) {
+                    self.index.remove(slot, &rows)?;
+                }
+            }
+            self.bytes -= segment.memory_size();
+            if segment.last_slot < cutoff {
+                evicted.push(segment);
+            } else {
+                let (below, above) = segment.split_at(cutoff)?;
+                self.bytes += above.memory_size();
+                evicted.push(below);
+                kept.push(above);
+            }
+        }
+        kept.sort_by_key(|s| s.first_slot);
+        self.segments = kept;
+        if !evicted.is_empty() {
+            self.version += 1;
+        }
+        Ok(evicted)
+    }
+
+    /// Removes the finalized rows of exactly `slots` and returns how many slots were
+    /// removed, the other slots of the same segments stay
+    pub(crate) fn evict_slots(&mut self, slots: &BTreeSet<u64>) -> eyre::Result<usize> {
+        let mut evicted = 0;
+        for slot in slots {
+            if let Some(segment) = self.recent.remove(slot) {
+                self.index.remove(*slot, &segment.transactions)?;
+                self.bytes -= segment.memory_size();
+                evicted += 1;
+            }
+        }
+        let mut kept = vec![];
+        for segment in std::mem::take(&mut self.segments) {
+            let (gone, left): (Vec<u64>, Vec<u64>) =
+                segment.slots().into_iter().partition(|s| slots.contains(s));
+            if gone.is_empty() {
+                kept.push(segment);
+                continue;
+            }
+            for slot in &gone {
+                if let Some(rows) = segment.slot_transactions(*slot) {
+                    self.index.remove(*slot, &rows)?;
+                }
+            }
+            self.bytes -= segment.memory_size();
+            evicted += gone.len();
+            if !left.is_empty() {
+                let rest = segment.without_slots(slots)?;
+                self.bytes += rest.memory_size();
+                kept.push(rest);
+            }
+        }
+        self.segments = kept;
+        if evicted > 0 {
+            self.version += 1;
+        }
+        Ok(evicted)
+    }
+
+    pub(crate) fn memory_report(&self) -> MemoryReport {
+        let mut report = MemoryReport {
+            segments: self.segments.len(),
+            recent_slots: self.recent.len(),
+            pending_slots: self.pending.len(),
+            transaction_rows: self.size(),
+            indexed_signatures: self.index.len(),
+            epochs: self.epochs(),
+            ..Default::default()
+        };
+        for s in self.in_range(0, u64::MAX) {
+            report.transactions_bytes += s.transactions.get_array_memory_size();
+            report.votes_bytes += s.votes.as_ref().map_or(0, |v| v.get_array_memory_size());
+            report.blocks_bytes += s.blocks.get_array_memory_size();
+            report.rewards_bytes += s.rewards.get_array_memory_size();
+        }
+        report.pending_bytes = self.pending.values().map(|s| s.memory_size()).sum();
+        report.total_bytes = report.transactions_bytes
+            + report.votes_bytes
+            + report.blocks_bytes
+            + report.rewards_bytes;
+        report
+    }
+
+    /// The epochs with data in the store, oldest first
+    pub(crate) fn epochs(&self) -> Vec<u64> {
+        let epochs: std::collections::BTreeSet<u64> =
+            self.in_range(0, u64::MAX).map(|s| s.epoch).collect();
+        epochs.into_iter().collect()
+    }
+
+    /// The stored (slots, transaction rows) of an epoch, pending ones included
+    pub(crate) fn epoch_ingested(&self, epoch: u64) -> (u64, u64) {
+        self.in_range(0, u64::MAX)
+            .filter(|s| s.epoch == epoch)
+            .fold((0, 0), |(slots, rows), s| {
+                (
+                    slots + s.blocks.num_rows() as u64,
+                    rows + s.transactions.num_rows() as u64,
+                )
+            })
+    }
+
+    /// The (slot, block time) of the latest stored block with a known block time
+    pub(crate) fn latest_block_time(&self) -> Option<(u64, i64)> {
+        self.in_range(0, u64::MAX)
+            .filter_map(|s| s.latest_block_time())
+            .max()
+    }
+
+    /// Number of finalized transaction rows
+    pub(crate) fn size(&self) -> usize {
+        self.segments
+            .iter()
+            .chain(self.recent.values())
+            .map(|s| s.transactions.num_rows())
+            .sum()
+    }
+}
+
+/// The record batches of a contiguous slot range. Freshly ingested slots are
+/// single-slot segments, which are periodically coalesced into larger ones.
+#[derive(Debug, Clone)]
+pub(crate) struct Segment {
+    pub(crate) first_slot: u64,
+    pub(crate) last_slot: u64,
+    pub(crate) parent_slot: u64, //parent of first_slot
+    pub(crate) epoch: u64,
+    pub(crate) transactions: RecordBatch,
+    pub(crate) votes: Option<RecordBatch>,
+    pub(crate) blocks: RecordBatch,
+    pub(crate) rewards: RecordBatch,
+    //NOTE row range of each slot in `transactions`, rows are grouped by slot
+    pub(crate) tx_rows: BTreeMap<u64, Range<usize>>,
+}
+
+impl Segment {
+    pub(crate) fn contains(&self, slot: u64) -> bool {
+        (self.first_slot..=self.last_slot).contains(&slot)
+    }
+
+    pub(crate) fn overlaps(&self, first_slot: u64, last_slot: u64) -> bool {
+        self.first_slot <= last_slot && first_slot <= self.last_slot
+    }
+
+    /// Slots holding a block in this segment
+    pub(crate) fn slots(&self) -> Vec<u64> {
+        self.blocks
+            .column_by_name("slot")
+            .and_then(|c| c.as_primitive_opt::<UInt64Type>())
+            .map_or(vec![], |slots| slots.values().to_vec())
+    }
+
+    ///NOTE skipped slots leave holes in a coalesced segment's range, so check its blocks
+    fn has_block(&self, slot: u64) -> bool {
+        self.blocks
+            .column_by_name("slot")
+            .and_then(|c| c.as_any().downcast_ref::<UInt64Array>())
+            .is_some_and(|slots| slots.values().contains(&slot))
+    }
+
+    /// The transaction rows of `slot`, if any is stored in this segment
+    pub(crate) fn slot_transactions(&self, slot: u64) -> Option<RecordBatch> {
+        self.tx_rows
+            .get(&slot)
+            .map(|r| self.transactions.slice(r.start, r.len()))
+    }
+
+    /// Bytes held by the Arrow buffers of the segment's batches
+    pub(crate) fn memory_size(&self) -> usize {
+        self.transactions.get_array_memory_size()
+            + self.votes.as_ref().map_or(0, |v| v.get_array_memory_size())
+            + self.blocks.get_array_memory_size()
+            + self.rewards.get_array_memory_size()
+    }
+
+    /// The last slot of the segment whose block time is before `ts`
+    fn last_slot_before(&self, ts: i64) -> Option<u64> {
+        let slots = self
+            .blocks
+            .column_by_name("slot")?
+            .as_primitive_opt::<UInt64Type>()?;
+        let times = self
+            .blocks
+            .column_by_name("block_time")?
+            .as_primitive_opt::<TimestampSecondType>()?;
+        slots
+            .iter()
+            .zip(times.iter())
+            .filter_map(|(slot, time)| time.filter(|t| *t < ts).and(slot))
+            .max()
+    }
+
+    /// The (slot, block time) of the segment's latest block with a known block time
+    fn latest_block_time(&self) -> Option<(u64, i64)> {
+        let slots = self
+            .blocks
+            .column_by_name("slot")?
+            .as_primitive_opt::<UInt64Type>()?;
+        let times = self
+            .blocks
+            .column_by_name("block_time")?
+            .as_primitive_opt::<TimestampSecondType>()?;
+        slots
+            .iter()
+            .zip(times.iter())
+            .filter_map(|(slot, time)| Some((slot?, time?)))
+            .max()
+    }
+
+    /// The (min, max) `block_time` of the segment's blocks, if any is known
+    pub(crate) fn block_time_range(&self) -> Option<(i64, i64)> {
+        let times = self
+            .blocks
+            .column_by_name("block_time")?
+            .as_primitive_opt::<TimestampSecondType>()?;
+        Some((min(times)?, max(times)?))
+    }
+
+    /// Merges segments into one, rows keep the order of the given segments
+    pub(crate) fn coalesce(segments: &[Segment]) -> eyre::Result<Segment> {
+        let first = segments
+            .first()
+            .ok_or_else(|| eyre::eyre!("no segment to coalesce"))?;
+        let txs: Vec<&RecordBatch> = segments.iter().map(|s| &s.transactions).collect();
+        let votes: Vec<&RecordBatch> = segments.iter().filter_map(|s| s.votes.as_ref()).collect();
+        let blocks: Vec<&RecordBatch> = segments.iter().map(|s| &s.blocks).collect();
+        let rewards: Vec<&RecordBatch> = segments.iter().map(|s| &s.rewards).collect();
+        let transactions = concat(&txs)?.unwrap_or_else(|| first.transactions.clone());
+        Ok(Segment {
+            first_slot: segments
+                .iter()
+                .map(|s| s.first_slot)
+                .min()
+                .unwrap_or_default(),
+            last_slot: segments
+                .iter()
+                .map(|s| s.last_slot)
+                .max()
+                .unwrap_or_default(),
+            parent_slot: first.parent_slot,
+            epoch: first.epoch,
+            transactions: transactions.clone(),
+            votes: concat(&votes)?,
+            blocks: concat(&blocks)?.unwrap_or_else(|| first.blocks.clone()),
+            rewards: concat(&rewards)?.unwrap_or_else(|| first.rewards.clone()),
+            tx_rows: slot_rows(&transactions)?,
+        })
+    }
+
+    /// Merges segments into one sorted by slot, with deduplicated pubkey dictionaries
+    pub(crate) fn compact(segments: &[Segment]) -> eyre::Result<Segment> {
+        let merged = Segment::coalesce(segments)?;
+        let transactions = encode_pubkeys(&sort_by_slot(&merged.transactions)?)?;
+        Ok(Segment {
+            first_slot: merged.first_slot,
+            last_slot: merged.last_slot,
+            parent_slot: merged.parent_slot,
+            epoch: merged.epoch,
+            tx_rows: slot_rows(&transactions)?,
+            transactions,
+            votes: merged
+                .votes
+                .as_ref()
+                .map(|v| encode_pubkeys(&sort_by_slot(v)?))
+                .transpose()?,
+            blocks: sort_by_slot(&merged.blocks)?,
+            rewards: sort_by_slot(&merged.rewards)?,
+        })
+    }
+
+    /// A copy of this segment without the rows of `slot`
+    pub(crate) fn without_slot(&self, slot: u64) -> eyre::Result<Segment> {
+        self.filter_slots(|slots| neq(slots, &UInt64Array::new_scalar(slot)))
+    }
+
+    /// A copy of this segment without the rows of `slots`, its range narrowed to the
+    /// slots left
+    pub(crate) fn without_slots(&self, slots: &BTreeSet<u64>) -> eyre::Result<Segment> {
+        let rest = self.filter_slots(|column| {
+            Ok(column
+                .as_primitive::<UInt64Type>()
+                .iter()
+                .map(|s| s.map(|s| !slots.contains(&s)))
+                .collect())
+        })?;
+        let left = rest.slots();
+        let (Some(first_slot), Some(last_slot)) = (left.iter().min(), left.iter().max()) else {
+            return Ok(rest);
+        };
+        let parent_slot = rest
+            .blocks
+            .column_by_name("parent_slot")
+            .and_then(|c| c.as_primitive_opt::<UInt64Type>())
+            .and_then(|c| {
+                left.iter()
+                    .position(|s| s == first_slot)
+                    .map(|i| c.value(i))
+            })
+            .unwrap_or(self.parent_slot);
+        Ok(Segment {
+            first_slot: *first_slot,
+            last_slot: *last_slot,
+            parent_slot,
+            ..rest
+        })
+    }
+
+    /// Splits the segment into the rows below `slot` and the rest
+    pub(crate) fn split_at(&self, slot: u64) -> eyre::Result<(Segment, Segment)> {
+        let below = self.filter_slots(|slots| lt(slots, &UInt64Array::new_scalar(slot)))?;
+        let above = self.filter_slots(|slots| gt_eq(slots, &UInt64Array::new_scalar(slot)))?;
+        //NOTE blocks are sorted by slot once compacted, the first one holds the parent
+        let parent_slot = above
+            .blocks
+            .column_by_name("parent_slot")
+            .and_then(|c| c.as_primitive_opt::<UInt64Type>())
+            .filter(|c| !c.is_empty())
+            .map_or(slot.saturating_sub(1), |c| c.value(0));
+        Ok((
+            Segment {
+                last_slot: slot - 1,
+                ..below
+            },
+            Segment {
+                first_slot: slot,
+                parent_slot,
+                ..above
+            },
+        ))
+    }
+
+    /// A copy of this segment keeping the rows whose slot matches the predicate
+    fn filter_slots(
+        &self,
+        predicate: impl Fn(&ArrayRef) -> Result<BooleanArray, ArrowError>,
+    ) -> eyre::Result<Segment> {
+        let filter = |batch: &RecordBatch| -> eyre::Result<RecordBatch> {
+            let slots = batch
+                .column_by_name("slot")
+                .ok_or_else(|| eyre::eyre!("no slot column in batch"))?;
+            let keep = predicate(slots)?;
+            Ok(filter_record_batch(batch, &keep)?)
+        };
+        let transactions = filter(&self.transactions)?;
+        Ok(Segment {
+            first_slot: self.first_slot,
+            last_slot: self.last_slot,
+            parent_slot: self.parent_slot,
+            epoch: self.epoch,
+            tx_rows: slot_rows(&transactions)?,
+            transactions,
+            votes: self.votes.as_ref().map(filter).transpose()?,
+            blocks: filter(&self.blocks)?,
+            rewards: filter(&self.rewards)?,
+        })
+    }
+
+    /// A copy of this segment with the `commitment` columns rewritten to the given level
+    pub(crate) fn with_commitment(&self, commitment: Commitment) -> eyre::Result<Segment> {
+        Ok(Segment {
+            first_slot: self.first_slot,
+            last_slot: self.last_slot,
+            parent_slot: self.parent_slot,
+            epoch: self.epoch,
+            transactions: set_commitment(&self.transactions, commitment)?,
+            votes: self
+                .votes
+                .as_ref()
+                .map(|v| set_commitment(v, commitment))
+                .transpose()?,
+            blocks: set_commitment(&self.blocks, commitment)?,
+            rewards: set_commitment(&self.rewards, commitment)?,
+            tx_rows: self.tx_rows.clone(),
+        })
+    }
+}
+
+/// The row range of each slot in a batch whose rows are grouped by slot
+pub(crate) fn slot_rows(batch: &RecordBatch) -> eyre::Result<BTreeMap<u64, Range<usize>>> {
+    let slots = batch
+        .column_by_name("slot")
+        .and_then(|c| c.as_primitive_opt::<UInt64Type>())
+        .ok_or_else(|| eyre::eyre!("no slot column in batch"))?;
+    let mut rows: BTreeMap<u64, Range<usize>> = BTreeMap::new();
+    for (i, slot) in slots.values().iter().enumerate() {
+        rows.entry(*slot)
+            .and_modify(|r| r.end = i + 1)
+            .or_insert(i..i + 1);
+    }
+    Ok(rows)
+}
+
+fn concat(batches: &[&RecordBatch]) -> eyre::Result<Option<RecordBatch>> {
+    match batches.first() {
+        Some(b) => Ok(Some(concat_batches(&b.schema(), batches.iter().copied())?)),
+        None => Ok(None),
+    }
+}
+
+/// Rewrites the `commitment` column of a batch to the given level
+fn set_commitment(batch: &RecordBatch, commitment: Commitment) -> eyre::Result<RecordBatch> {
+    let idx = batch.schema().index_of("commitment")?;
+    let mut columns = batch.columns().to_vec();
+    columns[idx] = Arc::new(StringArray::from(vec![
+        commitment.as_str();
+        batch.num_rows()
+    ]));
+    Ok(RecordBatch::try_new(batch.schema(), columns)?)
+}
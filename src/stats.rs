@@ -0,0 +1,198 @@
+use serde::Deserialize;
+
+/// Default and maximum number of rows of the ranking endpoints
+const DEFAULT_TOP: usize = 10;
+const MAX_TOP: usize = 1000;
+
+//NOTE `amount` is the fee payer's balance change, fee included, so the lamports moved
+//     by a successful system transfer are the amount without the fee
+const TRANSFERRED: &str = "CASE WHEN tx_type = 'system_transfer' AND err IS NULL \
+                           THEN amount - CAST(fee AS BIGINT) ELSE 0 END";
+
+/// Width of the `block_time` buckets of the transaction stats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub(crate) enum Bucket {
+    #[serde(rename = "1m")]
+    Minute,
+    #[default]
+    #[serde(rename = "1h")]
+    Hour,
+    #[serde(rename = "1d")]
+    Day,
+}
+
+impl Bucket {
+    fn unit(&self) -> &'static str {
+        match self {
+            Bucket::Minute => "minute",
+            Bucket::Hour => "hour",
+            Bucket::Day => "day",
+        }
+    }
+}
+
+/// What the top accounts are ranked by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RankBy {
+    #[default]
+    Volume,
+    Count,
+}
+
+/// Per bucket count, success rate, fees and transfer volume, oldest bucket first.
+/// Rows without a block time are left out.
+pub(crate) fn transactions_sql(bucket: Bucket) -> String {
+    format!(
+        "SELECT date_trunc('{}', block_time) AS bucket, \
+                count(1) AS count, \
+                avg(CASE WHEN err IS NULL THEN 1.0 ELSE 0.0 END) AS success_rate, \
+                sum(fee) AS total_fees, \
+                avg(fee) AS avg_fee, \
+                approx_percentile_cont(fee, 0.5) AS p50_fee, \
+                approx_percentile_cont(fee, 0.9) AS p90_fee, \
+                approx_percentile_cont(fee, 0.99) AS p99_fee, \
+                sum({TRANSFERRED}) AS transfer_volume \
+         FROM transactions WHERE block_time IS NOT NULL \
+         GROUP BY 1 ORDER BY 1",
+        bucket.unit()
+    )
+}
+
+/// The fee payers with the most transferred lamports or transactions
+pub(crate) fn top_accounts_sql(by: RankBy, limit: Option<usize>) -> String {
+    let order = match by {
+        RankBy::Volume => "volume",
+        RankBy::Count => "count",
+    };
+    format!(
+        "SELECT CAST(sender AS VARCHAR) AS account, \
+                count(1) AS count, \
+                sum({TRANSFERRED}) AS volume \
+         FROM transactions GROUP BY 1 \
+         ORDER BY {order} DESC, account LIMIT {}",
+        top(limit)
+    )
+}
+
+/// The invoked programs by number of transactions, with their success rate, fees and
+/// compute units
+pub(crate) fn programs_sql(limit: Option<usize>) -> String {
+    format!(
+        "SELECT program_id, \
+                count(1) AS count, \
+                avg(CASE WHEN err IS NULL THEN 1.0 ELSE 0.0 END) AS success_rate, \
+                sum(fee) AS total_fees, \
+                sum(compute_units_consumed) AS compute_units \
+         FROM (SELECT unnest(program_ids) AS program_id, err, fee, compute_units_consumed \
+               FROM transactions) \
+         GROUP BY 1 ORDER BY count DESC, program_id LIMIT {}",
+        top(limit)
+    )
+}
+
+fn top(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_TOP).clamp(1, MAX_TOP)
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::types::{to_record_batch, Transaction, TxType};
+    use datafusion::prelude::SessionContext;
+    use serde_json::Value;
+
+    async fn query(sql: &str) -> eyre::Result<Vec<Value>> {
+        let rows = [
+            // (block_time, sender, tx_type, err, fee, amount, programs)
+            (
+                60,
+                "A",
+                TxType::SystemTransfer,
+                None,
+                5000,
+                105_000,
+                vec!["P1"],
+            ),
+            (
+                90,
+                "A",
+                TxType::SystemTransfer,
+                Some("fail"),
+                5000,
+                5000,
+                vec!["P1"],
+            ),
+            (
+                120,
+                "B",
+                TxType::ProgramCall,
+                None,
+                7000,
+                7000,
+                vec!["P1", "P2"],
+            ),
+            (
+                3700,
+                "B",
+                TxType::SystemTransfer,
+                None,
+                5000,
+                55_000,
+                vec!["P1"],
+            ),
+        ];
+        let txs: Vec<Transaction> = rows
+            .iter()
+            .enumerate()
+            .map(
+                |(i, (time, sender, tx_type, err, fee, amount, programs))| Transaction {
+                    tx_type: *tx_type,
+                    err: err.map(str::to_string),
+                    block_time: Some(*time),
+                    fee: *fee,
+                    sender: sender.to_string(),
+                    receiver: "C".to_string(),
+                    amount: *amount,
+                    num_instructions: programs.len() as u32,
+                    account_keys: vec![sender.to_string(), "C".to_string()],
+                    program_ids: programs.iter().map(|p| p.to_string()).collect(),
+                    ..Transaction::mock(&format!("s{}", i), i as u64)
+                },
+            )
+            .collect();
+        let ctx = SessionContext::new();
+        ctx.register_batch(
+            "transactions",
+            to_record_batch(&txs, Transaction::get_arrow_scheme())?,
+        )?;
+        let batches = ctx.sql(sql).await?.collect().await?;
+        let json = crate::store::batches_to_json(&batches)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    #[tokio::test]
+    async fn test_stats() -> eyre::Result<()> {
+        let buckets = query(&transactions_sql(Bucket::Hour)).await?;
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0]["count"], 3);
+        assert_eq!(buckets[0]["success_rate"].as_f64(), Some(2.0 / 3.0));
+        assert_eq!(buckets[0]["total_fees"], 17000);
+        assert_eq!(buckets[0]["transfer_volume"], 100_000);
+        assert_eq!(buckets[1]["transfer_volume"], 50_000);
+        assert_eq!(query(&transactions_sql(Bucket::Minute)).await?.len(), 3);
+
+        let by_volume = query(&top_accounts_sql(RankBy::Volume, None)).await?;
+        assert_eq!(by_volume[0]["account"], "A");
+        assert_eq!(by_volume[0]["volume"], 100_000);
+        let by_count = query(&top_accounts_sql(RankBy::Count, Some(1))).await?;
+        assert_eq!(by_count.len(), 1);
+
+        let programs = query(&programs_sql(None)).await?;
+        assert_eq!(programs[0]["program_id"], "P1");
+        assert_eq!(programs[0]["count"], 4);
+        assert_eq!(programs[1]["program_id"], "P2");
+        assert_eq!(programs[1]["total_fees"], 7000);
+        Ok(())
+    }
+}
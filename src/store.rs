@@ -1,25 +1,97 @@
-use crate::fetch::{SolFetcher, SOL_RPC_URL};
-use datafusion::{arrow::array::RecordBatch, prelude::SessionContext};
-use log::trace;
+use crate::{
+    compact::{encode_pubkeys, spawn_compactor, stored_schema},
+    epoch::{summarize, EpochSummary},
+    fetch::{SolFetcher, SOL_RPC_URL},
+    filter::IngestFilter,
+    provider::{StoreTable, TableKind},
+    retention::{write_parquet, MemoryReport, Retention},
+    segment::{slot_rows, Segment, SegmentSet},
+    types::{to_record_batch, Block, Commitment, Reward, SlotData, Transaction, TxType},
+    view::{ContinuousView, ViewTable},
+};
+use chrono::Utc;
+use datafusion::{
+    arrow::{array::RecordBatch, datatypes::SchemaRef},
+    execution::{context::SQLOptions, SendableRecordBatchStream},
+    prelude::{col, lit, DataFrame, SessionConfig, SessionContext},
+};
+use log::{debug, error, trace};
 use rayon::prelude::*;
-use std::time::Instant;
+use solana_sdk::epoch_schedule::EpochSchedule;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Instant,
+};
+use tokio::{sync::broadcast, task::JoinHandle};
 
 const RPS_LIMIT: usize = 25;
 const MOCKED_EPOCH_INIT_LEN: u64 = 25; //NOTE: 30 rps for ankr
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+const APPENDED_CHANNEL_CAPACITY: usize = 256; //NOTE in slots
+const FINALIZED_CHANNEL_CAPACITY: usize = 256; //NOTE in slots
+/// DataFusion's default catalog and schema, the store's tables are registered there
+pub(crate) const CATALOG_NAME: &str = "datafusion";
+pub(crate) const SCHEMA_NAME: &str = "public";
+
+/// What to do with vote transactions at ingest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum VotePolicy {
+    /// store votes in `transactions` like any other transaction
+    #[default]
+    Keep,
+    /// discard votes before they reach the store
+    Drop,
+    /// store votes in their own `vote_transactions` table
+    Separate,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StoreConfig {
+    pub vote_policy: VotePolicy,
+    pub filter: IngestFilter,
+    pub commitment: Commitment,
+    pub retention: Retention,
+    /// keep the program log messages of transactions, they dominate the row size
+    pub keep_logs: bool,
+}
+
+/// Changes to already stored data that downstream consumers must apply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreEvent {
+    /// the rows of these slots were removed, the slots were on an abandoned fork
+    Rollback { slots: Vec<u64> },
+    /// the first slot of epoch `to` was stored, `first_slot` being the epoch's first slot
+    /// whether it was produced or skipped
+    EpochTransition { from: u64, to: u64, first_slot: u64 },
+}
 
 //TODO abstract the trait for different store implementations
 #[allow(unused)]
-#[derive(Debug)]
 pub(crate) struct TransactionStore {
-    pub(crate) tx_batches: Vec<RecordBatch>,
-    pub(crate) init_slot: u64,
+    //NOTE shared with the table providers of the long-lived query context
+    data: Arc<RwLock<SegmentSet>>,
+    ctx: SessionContext,
+    events: broadcast::Sender<StoreEvent>,
+    /// the transaction rows of each slot, as they are stored
+    appended: broadcast::Sender<RecordBatch>,
+    /// the transaction rows of each slot, once finalized
+    finalized: broadcast::Sender<(u64, RecordBatch)>,
+    //NOTE also registered as tables of the query context
+    views: RwLock<BTreeMap<String, Arc<ContinuousView>>>,
+    //NOTE the running write-then-evict round, at most one at a time
+    evicting: Option<JoinHandle<()>>,
     pub(crate) current_slot: u64,
-    pub(crate) start_slot_next_epoch: u64,
+    pub(crate) last_finalized_slot: u64,
+    //NOTE fetched once, epochs of any slot are computed locally from it
+    pub(crate) epoch_schedule: EpochSchedule,
+    pub(crate) current_epoch: u64,
     mocked: bool,
+    pub(crate) config: StoreConfig,
 }
 
 impl TransactionStore {
-    pub(crate) fn new(mocked: bool) -> eyre::Result<Self> {
+    pub(crate) fn new(mocked: bool, config: StoreConfig) -> eyre::Result<Self> {
         let sol_fetcher = SolFetcher::new(SOL_RPC_URL);
         let cur_epoch = sol_fetcher.get_current_epoch()?;
         let init_slot = cur_epoch.current_slot();
@@ -30,17 +102,19 @@ impl TransactionStore {
         };
 
         let range: Vec<u64> = (start_slot..=init_slot).collect();
-        let mut tx_batches = Vec::with_capacity(range.len());
+        let epoch_schedule = sol_fetcher.get_epoch_schedule()?;
+        let mut store = Self::empty(mocked, config, init_slot, epoch_schedule)?;
         let windows = range.chunks(RPS_LIMIT);
         trace!("slot windows: {:#?}", windows);
         let mut timer = Instant::now();
         for window in windows {
-            let bs: eyre::Result<Vec<RecordBatch>> = window
+            let bs: eyre::Result<Vec<SlotData>> = window
                 .par_iter()
-                .map(|slot| sol_fetcher.fetch_transactions_as_batch_sync(*slot))
+                .map(|slot| sol_fetcher.fetch_slot_data_sync(*slot))
                 .collect();
-            for b in bs? {
-                tx_batches.push(b);
+            for (slot, b) in window.iter().zip(bs?) {
+                let segment = store.to_segment(*slot, b)?;
+                store.data_mut().push(*slot, segment)?;
             }
             //NOTE mini rate limiting
             let elapsed = timer.elapsed().as_millis() as u64;
@@ -52,65 +126,480 @@ impl TransactionStore {
             }
             timer = Instant::now();
         }
-        let start_slot_next_epoch = cur_epoch.start_slot_next_epoch();
+        Ok(store)
+    }
+
+    pub(crate) fn empty(
+        mocked: bool,
+        config: StoreConfig,
+        init_slot: u64,
+        epoch_schedule: EpochSchedule,
+    ) -> eyre::Result<Self> {
+        let data = Arc::new(RwLock::new(SegmentSet::default()));
+        //NOTE the information schema lets SQL clients and BI tools discover the tables
+        let ctx =
+            SessionContext::new_with_config(SessionConfig::new().with_information_schema(true));
+        let tx_schema = Arc::new(stored_schema(&Transaction::get_arrow_scheme()));
+        let mut tables = vec![
+            ("transactions", TableKind::Transactions, tx_schema.clone()),
+            (
+                "blocks",
+                TableKind::Blocks,
+                Arc::new(Block::get_arrow_scheme()),
+            ),
+            (
+                "rewards",
+                TableKind::Rewards,
+                Arc::new(Reward::get_arrow_scheme()),
+            ),
+        ];
+        if config.vote_policy == VotePolicy::Separate {
+            tables.push(("vote_transactions", TableKind::Votes, tx_schema));
+        }
+        for (name, kind, schema) in tables {
+            let table = StoreTable::new(kind, schema, data.clone());
+            ctx.register_table(name, Arc::new(table))?;
+        }
         Ok(Self {
-            tx_batches,
-            start_slot_next_epoch,
-            init_slot,
+            data,
+            ctx,
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            appended: broadcast::channel(APPENDED_CHANNEL_CAPACITY).0,
+            finalized: broadcast::channel(FINALIZED_CHANNEL_CAPACITY).0,
+            views: RwLock::default(),
+            evicting: None,
             current_slot: init_slot,
+            last_finalized_slot: init_slot,
+            current_epoch: epoch_schedule.get_epoch(init_slot),
+            epoch_schedule,
             mocked,
+            config,
         })
     }
 
-    pub(crate) fn append_batch(&mut self, batch: RecordBatch, slot: u64) {
+    fn data(&self) -> RwLockReadGuard<'_, SegmentSet> {
+        self.data.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn data_mut(&self) -> RwLockWriteGuard<'_, SegmentSet> {
+        self.data.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn append_batch(&mut self, data: SlotData, slot: u64) -> eyre::Result<()> {
         //NOTE: corner case#2 handle epoch switch, onece per epoch
-        if slot == self.start_slot_next_epoch {
-            self.tx_batches.clear();
+        //      on the first slot seen past the boundary, the boundary slot itself may be
+        //      skipped; the new epoch is opened before that slot is stored, nothing is
+        //      cleared: the previous epoch stays queryable until retention evicts it
+        let epoch = self.epoch_of(slot);
+        if epoch > self.current_epoch {
+            let from = self.current_epoch;
+            self.current_epoch = epoch;
+            let first_slot = self.epoch_schedule.get_first_slot_in_epoch(epoch);
+            debug!(
+                "--- epoch transition: {} -> {}, first slot: {}, at slot: {}",
+                from, epoch, first_slot, slot
+            );
+            //NOTE no receiver is not an error
+            let _ = self.events.send(StoreEvent::EpochTransition {
+                from,
+                to: epoch,
+                first_slot,
+            });
         }
-        if slot >= self.start_slot_next_epoch {
-            let sol_fetcher = SolFetcher::new(SOL_RPC_URL);
-            if let Ok(cur_epoch) = sol_fetcher.get_current_epoch() {
-                self.start_slot_next_epoch = cur_epoch.start_slot_next_epoch();
+        let commitment = data.commitment;
+        let segment = self.to_segment(slot, data)?;
+        let transactions = segment.transactions.clone();
+        if commitment == Commitment::Confirmed {
+            self.data_mut().insert_pending(slot, segment)?;
+        } else {
+            self.data_mut().push(slot, segment)?;
+            self.enforce_retention()?;
+            if transactions.num_rows() > 0 {
+                let _ = self.finalized.send((slot, transactions.clone()));
             }
         }
-        self.tx_batches.push(batch);
+        //NOTE pushed once queryable, confirmed rows may still be rolled back
+        if transactions.num_rows() > 0 {
+            let _ = self.appended.send(transactions);
+        }
+        Ok(())
+    }
+
+    /// Evicts the finalized slots the retention policy no longer covers, to Parquet
+    /// files if configured so
+    fn enforce_retention(&mut self) -> eyre::Result<()> {
+        //NOTE the next round starts once the running one is done, it covers this slot too
+        if self
+            .evicting
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
+        {
+            return Ok(());
+        }
+        let retention = &self.config.retention;
+        let now = Utc::now().timestamp();
+        let cutoff = retention.cutoff(
+            &self.data(),
+            self.current_slot,
+            self.current_epoch,
+            &self.epoch_schedule,
+            now,
+        );
+        let Some(cutoff) = cutoff else {
+            return Ok(());
+        };
+        let Some(dir) = retention.evict_to.clone() else {
+            let evicted = self.data_mut().evict_before(cutoff)?;
+            if !evicted.is_empty() {
+                debug!("--- evicted {} segments", evicted.len());
+            }
+            return Ok(());
+        };
+        let below = self.data().before(cutoff)?;
+        if below.is_empty() {
+            return Ok(());
+        }
+        //NOTE the rows stay queryable until written, the write runs outside the lock;
+        //     only the slots written are evicted, the ones of a failed write and the
+        //     ones stored below the cutoff since the snapshot wait for the next round
+        let data = self.data.clone();
+        self.evicting = Some(tokio::task::spawn_blocking(move || {
+            let mut written = BTreeSet::new();
+            for segment in &below {
+                match write_parquet(&dir, segment) {
+                    Ok(()) => written.extend(segment.slots()),
+                    Err(err) => error!(
+                        "failed to write evicted slots {}..={}; error = {}",
+                        segment.first_slot, segment.last_slot, err
+                    ),
+                }
+            }
+            if written.is_empty() {
+                return;
+            }
+            let mut data = data.write().unwrap_or_else(|e| e.into_inner());
+            match data.evict_slots(&written) {
+                Ok(evicted) => debug!("--- evicted {} slots", evicted),
+                Err(err) => error!("failed to evict slots below {}; error = {}", cutoff, err),
+            }
+        }));
+        Ok(())
+    }
+
+    pub(crate) fn memory_report(&self) -> MemoryReport {
+        self.data().memory_report()
     }
 
-    pub(crate) async fn query(
+    /// Whether the rows of a finalized `slot` are already stored
+    pub(crate) fn has_slot(&self, slot: u64) -> bool {
+        self.data().has_slot(slot)
+    }
+
+    /// Confirmed slots up to `root` that are still waiting for reconciliation
+    pub(crate) fn pending_slots(&self, root: u64) -> Vec<u64> {
+        self.data()
+            .pending
+            .range(..=root)
+            .map(|(s, _)| *s)
+            .collect()
+    }
+
+    /// Starts merging small slot segments into large sorted ones in the background
+    pub(crate) fn start_compactor(&self) -> JoinHandle<()> {
+        spawn_compactor(self.data.clone())
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<StoreEvent> {
+        self.events.subscribe()
+    }
+
+    /// Subscribes to the transaction rows of each slot as they are stored
+    pub(crate) fn subscribe_transactions(&self) -> broadcast::Receiver<RecordBatch> {
+        self.appended.subscribe()
+    }
+
+    /// Subscribes to the transaction rows of each slot once finalized, with the slot
+    pub(crate) fn subscribe_finalized(&self) -> broadcast::Receiver<(u64, RecordBatch)> {
+        self.finalized.subscribe()
+    }
+
+    /// Reconciles pending slots up to `root` against the slots known to be finalized:
+    /// finalized ones are promoted, the rest were on a dead fork and get dropped,
+    /// together with any pending descendants built on top of them.
+    /// Returns the orphaned slots, which are also broadcast as a `StoreEvent::Rollback`.
+    pub(crate) fn finalize(&mut self, root: u64, finalized: &[u64]) -> eyre::Result<Vec<u64>> {
+        //NOTE hold the write lock throughout so queries never see a half reconciled store
+        let mut data = self.data_mut();
+        let mut finalized_slots = vec![];
+        let mut orphaned = vec![];
+        for (slot, _) in data.pending.range(..=root) {
+            if finalized.binary_search(slot).is_ok() {
+                finalized_slots.push(*slot);
+            } else {
+                orphaned.push(*slot);
+            }
+        }
+        //NOTE slots above the root can not be finalized yet, but they are dead
+        //     as soon as one of their ancestors is
+        let mut dead: HashSet<u64> = orphaned.iter().copied().collect();
+        for (slot, segment) in data.pending.range(root + 1..) {
+            if dead.contains(&segment.parent_slot) {
+                dead.insert(*slot);
+                orphaned.push(*slot);
+            }
+        }
+
+        //NOTE validate everything before mutating
+        let promoted = finalized_slots
+            .iter()
+            .map(|slot| {
+                Ok((
+                    *slot,
+                    data.pending[slot].with_commitment(Commitment::Finalized)?,
+                ))
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+        let mut promoted_rows = vec![];
+        for (slot, segment) in promoted {
+            if segment.transactions.num_rows() > 0 {
+                promoted_rows.push((slot, segment.transactions.clone()));
+            }
+            data.remove_pending(slot)?;
+            data.push(slot, segment)?;
+        }
+        for slot in &orphaned {
+            debug!("--- drop orphaned slot: {}", slot);
+            data.remove_pending(*slot)?;
+        }
+        drop(data);
+        self.last_finalized_slot = self.last_finalized_slot.max(root);
+        self.enforce_retention()?;
+        for rows in promoted_rows {
+            let _ = self.finalized.send(rows);
+        }
+        if !orphaned.is_empty() {
+            //NOTE no receiver is not an error
+            let _ = self.events.send(StoreEvent::Rollback {
+                slots: orphaned.clone(),
+            });
+        }
+        Ok(orphaned)
+    }
+
+    pub(crate) fn epoch_of(&self, slot: u64) -> u64 {
+        self.epoch_schedule.get_epoch(slot)
+    }
+
+    /// Schedule, progress and ingest summary of an epoch, the current one by default
+    pub(crate) fn epoch_summary(&self, epoch: Option<u64>) -> Option<EpochSummary> {
+        let epoch = epoch.unwrap_or(self.current_epoch);
+        if epoch > self.current_epoch.saturating_add(1) {
+            return None;
+        }
+        let data = self.data();
+        summarize(
+            &self.epoch_schedule,
+            epoch,
+            self.current_slot,
+            data.latest_block_time(),
+            data.epoch_ingested(epoch),
+        )
+    }
+
+    fn to_segment(&self, slot: u64, mut data: SlotData) -> eyre::Result<Segment> {
+        let epoch = self.epoch_of(slot);
+        data.block.epoch = epoch;
+        data.rewards.iter_mut().for_each(|r| r.epoch = epoch);
+        let filter = &self.config.filter;
+        let txs = data
+            .transactions
+            .into_iter()
+            .filter(|tx| filter.matches(tx))
+            .map(|mut tx| {
+                tx.epoch = epoch;
+                if !self.config.keep_logs {
+                    tx.log_messages = None;
+                }
+                tx
+            });
+        let schema = Transaction::get_arrow_scheme;
+        let (txs, votes) = match self.config.vote_policy {
+            VotePolicy::Keep => (txs.collect::<Vec<_>>(), None),
+            VotePolicy::Drop => (txs.filter(|tx| tx.tx_type != TxType::Vote).collect(), None),
+            VotePolicy::Separate => {
+                let (votes, txs): (Vec<Transaction>, Vec<Transaction>) =
+                    txs.partition(|tx| tx.tx_type == TxType::Vote);
+                (
+                    txs,
+                    Some(encode_pubkeys(&to_record_batch(&votes, schema())?)?),
+                )
+            }
+        };
+        let transactions = encode_pubkeys(&to_record_batch(&txs, schema())?)?;
+        Ok(Segment {
+            first_slot: slot,
+            last_slot: slot,
+            parent_slot: data.block.parent_slot,
+            epoch,
+            tx_rows: slot_rows(&transactions)?,
+            transactions,
+            votes,
+            blocks: to_record_batch(&[data.block], Block::get_arrow_scheme())?,
+            rewards: to_record_batch(&data.rewards, Reward::get_arrow_scheme())?,
+        })
+    }
+
+    /// Point lookup of a transaction through the signature index
+    pub(crate) fn transaction_by_signature(&self, signature: &str) -> Option<RecordBatch> {
+        let data = self.data();
+        let pos = data.index.signature(signature)?;
+        data.rows(&[pos]).pop()
+    }
+
+    pub(crate) async fn query(&self, sql: &str) -> eyre::Result<Vec<RecordBatch>> {
+        let result = self.dataframe(sql).await?;
+        Ok(result.collect().await?)
+    }
+
+    /// Runs `sql` lazily, batches are produced as the stream is polled. The stream
+    /// holds its own snapshot of the scanned segments, so no lock is kept on the store.
+    pub(crate) async fn query_stream(&self, sql: &str) -> eyre::Result<SendableRecordBatchStream> {
+        let result = self.dataframe(sql).await?;
+        Ok(result.execute_stream().await?)
+    }
+
+    /// The schema of the rows `sql` would return, the query is planned but not run
+    pub(crate) async fn query_schema(&self, sql: &str) -> eyre::Result<SchemaRef> {
+        let result = self.dataframe(sql).await?;
+        Ok(result.schema().inner().clone())
+    }
+
+    /// The tables registered in the session, by name, with their schemas
+    pub(crate) async fn tables(&self) -> eyre::Result<Vec<(String, SchemaRef)>> {
+        let Some(schema) = self
+            .ctx
+            .catalog(CATALOG_NAME)
+            .and_then(|c| c.schema(SCHEMA_NAME))
+        else {
+            return Ok(vec![]);
+        };
+        let mut tables = vec![];
+        for name in schema.table_names() {
+            if let Some(table) = schema.table(&name).await? {
+                tables.push((name, table.schema()));
+            }
+        }
+        tables.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(tables)
+    }
+
+    pub(crate) async fn schema(&self, table_name: &str) -> eyre::Result<SchemaRef> {
+        Ok(self.ctx.table_provider(table_name).await?.schema())
+    }
+
+    async fn dataframe(&self, sql: &str) -> eyre::Result<DataFrame> {
+        //NOTE queries are read-only, DDL, DML and `SET` would change the shared context
+        let options = SQLOptions::new()
+            .with_allow_ddl(false)
+            .with_allow_dml(false)
+            .with_allow_statements(false);
+        Ok(self.ctx.sql_with_options(sql, options).await?)
+    }
+
+    /// The finalized transactions, what continuous views are computed over
+    pub(crate) async fn finalized_transactions(&self) -> eyre::Result<DataFrame> {
+        let transactions = self.ctx.table("transactions").await?;
+        Ok(transactions.filter(col("commitment").eq(lit(Commitment::Finalized.as_str())))?)
+    }
+
+    /// Registers the continuous view `name`, computed over the finalized transactions
+    /// stored so far and then maintained as slots get finalized
+    pub(crate) async fn create_view(
         &self,
+        name: &str,
         sql: &str,
-        table_name: &str,
-    ) -> eyre::Result<Vec<RecordBatch>> {
-        let ctx = SessionContext::new();
-        let df = ctx.read_batches(self.tx_batches.clone())?;
-        ctx.register_table(table_name, df.into_view())?;
-        let result = ctx.sql(sql).await?;
-        Ok(result.collect().await?)
+    ) -> eyre::Result<Arc<ContinuousView>> {
+        let valid_name = name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid_name {
+            eyre::bail!("invalid view name: {}", name);
+        }
+        if self.ctx.table_exist(name)? {
+            eyre::bail!("table already exists: {}", name);
+        }
+        let source = self.finalized_transactions().await?;
+        let view = Arc::new(ContinuousView::create(name, sql, source).await?);
+        self.ctx
+            .register_table(name, Arc::new(ViewTable::new(view.clone())))?;
+        self.views
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(name.to_string(), view.clone());
+        Ok(view)
     }
 
-    pub(crate) async fn query_to_json(&self, sql: &str, table_name: &str) -> eyre::Result<String> {
-        let batches = self.query(sql, table_name).await?;
-        let buf = Vec::new();
-        let mut writer = arrow_json::ArrayWriter::new(buf);
-        for batch in &batches {
-            writer.write(batch)?;
+    /// Returns whether the view existed
+    pub(crate) fn drop_view(&self, name: &str) -> eyre::Result<bool> {
+        let removed = self
+            .views
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(name);
+        if removed.is_some() {
+            self.ctx.deregister_table(name)?;
         }
-        writer.finish().unwrap();
-        let json_data = writer.into_inner();
-        let json_string = String::from_utf8(json_data)?;
-        Ok(json_string)
+        Ok(removed.is_some())
+    }
+
+    pub(crate) fn views(&self) -> Vec<Arc<ContinuousView>> {
+        let views = self.views.read().unwrap_or_else(|e| e.into_inner());
+        views.values().cloned().collect()
+    }
+
+    pub(crate) fn view(&self, name: &str) -> Option<Arc<ContinuousView>> {
+        let views = self.views.read().unwrap_or_else(|e| e.into_inner());
+        views.get(name).cloned()
+    }
+
+    pub(crate) async fn query_to_json(&self, sql: &str) -> eyre::Result<String> {
+        let batches = self.query(sql).await?;
+        batches_to_json(&batches)
     }
 
     #[allow(unused)]
     fn size(&self) -> usize {
-        self.tx_batches.iter().map(|b| b.num_rows()).sum()
+        self.data().size()
+    }
+}
+
+pub(crate) fn batches_to_json(batches: &[RecordBatch]) -> eyre::Result<String> {
+    let buf = Vec::new();
+    let mut writer = arrow_json::ArrayWriter::new(buf);
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    writer.finish().unwrap();
+    let json_data = writer.into_inner();
+    let json_string = String::from_utf8(json_data)?;
+    Ok(json_string)
+}
+
+#[cfg(test)]
+impl TransactionStore {
+    /// An empty store, without any RPC call
+    pub(crate) fn mock(config: StoreConfig) -> Self {
+        //NOTE a single epoch covering every slot of the tests
+        let schedule = EpochSchedule::custom(u64::MAX / 2, u64::MAX / 2, false);
+        Self::empty(true, config, 0, schedule).unwrap()
     }
 }
 
 #[cfg(test)]
 mod unit_tests {
     use super::*;
-    use chrono::Utc;
+    use crate::segment::SEGMENT_SLOTS;
 
     #[test]
     fn test_transaction_store_new() -> eyre::Result<()> {
@@ -119,12 +608,599 @@ mod unit_tests {
         //     .filter_module("solagg::store", log::LevelFilter::Trace)
         //     .init();
         let mocked = true;
-        let tx_store = TransactionStore::new(mocked)?;
+        let tx_store = TransactionStore::new(mocked, StoreConfig::default())?;
         println!("tx_store.size:\n{:#?}", tx_store.size());
         assert!(tx_store.size() > 0);
         Ok(())
     }
 
+    fn slot_data(slot: u64, parent_slot: u64, commitment: Commitment) -> SlotData {
+        let tx = Transaction {
+            commitment,
+            tx_type: TxType::SystemTransfer,
+            sender: "a".to_string(),
+            receiver: "b".to_string(),
+            amount: 1,
+            account_keys: vec!["a".to_string(), "b".to_string()],
+            ..Transaction::mock(&format!("sig{}", slot), slot)
+        };
+        let block = serde_json::json!({
+            "slot": slot,
+            "commitment": commitment,
+            "blockhash": format!("hash{}", slot),
+            "previous_blockhash": format!("hash{}", parent_slot),
+            "parent_slot": parent_slot,
+            "tx_count": 1,
+            "success_count": 1,
+            "failed_count": 0,
+            "total_fees": 5000,
+        });
+        let reward = serde_json::json!({
+            "slot": slot,
+            "commitment": commitment,
+            "pubkey": "v",
+            "lamports": 5000,
+            "post_balance": 1_000_000,
+            "reward_type": "Fee",
+            "commission": null,
+        });
+        SlotData {
+            commitment,
+            transactions: vec![tx],
+            block: serde_json::from_value(block).unwrap(),
+            rewards: vec![serde_json::from_value(reward).unwrap()],
+        }
+    }
+
+    #[test]
+    fn test_finalize_confirmed_slots() -> eyre::Result<()> {
+        let mut tx_store = TransactionStore::mock(StoreConfig {
+            commitment: Commitment::Confirmed,
+            ..Default::default()
+        });
+        let mut events = tx_store.subscribe();
+        //10 <- 11 <- 13 is a fork, 10 <- 12 <- 14 the canonical chain
+        for (slot, parent) in [(10, 9), (11, 10), (12, 10), (13, 11), (14, 12)] {
+            tx_store.append_batch(slot_data(slot, parent, Commitment::Confirmed), slot)?;
+        }
+        assert_eq!(tx_store.size(), 0);
+        assert_eq!(tx_store.pending_slots(u64::MAX), vec![10, 11, 12, 13, 14]);
+
+        let orphaned = tx_store.finalize(12, &[10, 12])?;
+        assert_eq!(orphaned, vec![11, 13]);
+        assert_eq!(
+            events.try_recv()?,
+            StoreEvent::Rollback {
+                slots: vec![11, 13]
+            }
+        );
+        assert_eq!(tx_store.pending_slots(u64::MAX), vec![14]);
+        assert_eq!(tx_store.last_finalized_slot, 12);
+        assert_eq!(tx_store.size(), 2);
+        //the indexes follow promotions and rollbacks
+        assert!(tx_store.transaction_by_signature("sig11").is_none());
+        assert!(tx_store.transaction_by_signature("sig12").is_some());
+        let data = tx_store.data();
+        assert_eq!(data.rows(&data.index.account("a")).len(), 3);
+        let promoted = &data.recent[&10];
+        for batch in [&promoted.transactions, &promoted.blocks, &promoted.rewards] {
+            let commitment = batch
+                .column_by_name("commitment")
+                .unwrap()
+                .as_any()
+                .downcast_ref::<datafusion::arrow::array::StringArray>()
+                .unwrap()
+                .value(0)
+                .to_string();
+            assert_eq!(commitment, "finalized");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compaction_during_ingest() -> eyre::Result<()> {
+        let stored = |slots: u64| -> eyre::Result<TransactionStore> {
+            let mut tx_store = TransactionStore::mock(StoreConfig::default());
+            for slot in 1..=slots {
+                tx_store.append_batch(slot_data(slot, slot - 1, Commitment::Finalized), slot)?;
+            }
+            Ok(tx_store)
+        };
+        let n = SEGMENT_SLOTS as u64;
+
+        //a slot arriving while a merge is built leaves it valid
+        let mut tx_store = stored(n)?;
+        let plan = tx_store.data().compaction_plan().unwrap();
+        tx_store.append_batch(slot_data(n + 1, n, Commitment::Finalized), n + 1)?;
+        assert!(tx_store.data_mut().apply_compaction(plan.build()?));
+        assert_eq!(tx_store.data().segments.len(), 1);
+        assert_eq!(
+            tx_store.data().recent.keys().copied().collect::<Vec<_>>(),
+            vec![n + 1]
+        );
+        assert_eq!(tx_store.size(), n as usize + 1);
+
+        //a replaced input does not
+        let mut tx_store = stored(n)?;
+        let plan = tx_store.data().compaction_plan().unwrap();
+        tx_store.append_batch(slot_data(1, 0, Commitment::Finalized), 1)?;
+        assert!(!tx_store.data_mut().apply_compaction(plan.build()?));
+        assert!(tx_store.data().segments.is_empty());
+        assert_eq!(tx_store.size(), n as usize);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_spawn_compactor() -> eyre::Result<()> {
+        let mut tx_store = TransactionStore::mock(StoreConfig::default());
+        let n = SEGMENT_SLOTS as u64;
+        for slot in 1..=n {
+            tx_store.append_batch(slot_data(slot, slot - 1, Commitment::Finalized), slot)?;
+        }
+        //the first round runs at once, while ingest goes on
+        let compactor = tx_store.start_compactor();
+        for slot in n + 1..=n + 8 {
+            tx_store.append_batch(slot_data(slot, slot - 1, Commitment::Finalized), slot)?;
+        }
+        let compacted = tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            while tx_store.data().segments.is_empty() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        compactor.abort();
+        assert!(compacted.is_ok(), "no segment compacted");
+        assert_eq!(tx_store.size(), n as usize + 8);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_slot_replacement_and_coalescing() -> eyre::Result<()> {
+        let mut tx_store = TransactionStore::mock(StoreConfig::default());
+        for slot in 1..=SEGMENT_SLOTS as u64 {
+            tx_store.append_batch(slot_data(slot, slot - 1, Commitment::Finalized), slot)?;
+        }
+        assert!(tx_store.data().segments.is_empty());
+        assert_eq!(crate::compact::compact(&tx_store.data)?, SEGMENT_SLOTS);
+        assert_eq!(tx_store.data().segments.len(), 1);
+        assert!(tx_store.data().recent.is_empty());
+        assert_eq!(tx_store.size(), SEGMENT_SLOTS);
+
+        //duplicate notifications replace, not duplicate, both recent and coalesced slots
+        let slot = SEGMENT_SLOTS as u64 + 1;
+        tx_store.append_batch(slot_data(slot, slot - 1, Commitment::Finalized), slot)?;
+        tx_store.append_batch(slot_data(slot, slot - 1, Commitment::Finalized), slot)?;
+        tx_store.append_batch(slot_data(7, 6, Commitment::Finalized), 7)?;
+        assert_eq!(tx_store.size(), SEGMENT_SLOTS + 1);
+        assert!(tx_store.has_slot(7));
+        assert!(tx_store.has_slot(slot));
+        assert!(!tx_store.has_slot(slot + 1));
+
+        //only the segments of the range are scanned
+        assert_eq!(tx_store.data().in_range(slot, slot).count(), 1);
+        assert_eq!(tx_store.data().in_range(1, 2).count(), 1);
+
+        //slot filters are pushed down to prune segments, rows are still filtered exactly
+        let sql = format!(
+            "SELECT count(1) FROM transactions WHERE slot >= {}",
+            slot - 1
+        );
+        let batches = tx_store.query(&sql).await?;
+        let value = batches[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<datafusion::arrow::array::Int64Array>()
+            .unwrap()
+            .value(0);
+        assert_eq!(value, 2);
+
+        //the indexes see replaced and coalesced rows once
+        let tx = tx_store.transaction_by_signature("sig7").unwrap();
+        assert_eq!(tx.num_rows(), 1);
+        let data = tx_store.data();
+        assert_eq!(data.rows(&data.index.account("b")).len(), SEGMENT_SLOTS + 1);
+        drop(data);
+        let sql = "SELECT slot FROM transactions WHERE signature = 'sig7'";
+        let batches = tx_store.query(sql).await?;
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 1);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_epoch_handover() -> eyre::Result<()> {
+        //epochs of 32 slots: epoch 5 starts at 160, epoch 6 at 192
+        let schedule = EpochSchedule::custom(32, 32, false);
+        let mut tx_store = TransactionStore::empty(true, StoreConfig::default(), 180, schedule)?;
+        let mut events = tx_store.subscribe();
+        //the boundary slot 192 is skipped
+        for slot in [189, 190, 193, 194, 195] {
+            tx_store.append_batch(slot_data(slot, slot - 1, Commitment::Finalized), slot)?;
+        }
+        assert_eq!(tx_store.epoch_of(191), 5);
+        assert_eq!(tx_store.epoch_of(192), 6);
+        assert_eq!(tx_store.current_epoch, 6);
+        assert_eq!(
+            events.try_recv()?,
+            StoreEvent::EpochTransition {
+                from: 5,
+                to: 6,
+                first_slot: 192
+            }
+        );
+        assert!(events.try_recv().is_err());
+        //the previous epoch is still there after the boundary
+        assert_eq!(tx_store.size(), 5);
+
+        //the ended epoch gets sealed into its own segment
+        crate::compact::compact(&tx_store.data)?;
+        let data = tx_store.data();
+        assert_eq!(data.segments.len(), 1);
+        assert_eq!(data.segments[0].epoch, 5);
+        assert_eq!(data.epochs(), vec![5, 6]);
+        drop(data);
+
+        let sql = "SELECT epoch, count(1) AS n FROM transactions GROUP BY epoch ORDER BY epoch";
+        let json = tx_store.query_to_json(sql).await?;
+        assert_eq!(json, r#"[{"epoch":5,"n":2},{"epoch":6,"n":3}]"#);
+        Ok(())
+    }
+
+    #[test]
+    fn test_epoch_summary() -> eyre::Result<()> {
+        let schedule = EpochSchedule::custom(32, 32, false);
+        let mut tx_store = TransactionStore::mock(StoreConfig::default());
+        tx_store.epoch_schedule = schedule;
+        for slot in [64, 65, 67] {
+            tx_store.append_batch(slot_data(slot, slot - 1, Commitment::Finalized), slot)?;
+        }
+        tx_store.current_slot = 71;
+        let summary = tx_store.epoch_summary(None).unwrap();
+        assert_eq!(summary.epoch, 2);
+        assert_eq!((summary.first_slot, summary.last_slot), (64, 95));
+        assert_eq!(summary.progress, 0.25);
+        assert_eq!(summary.ingested_slots, 3);
+        assert_eq!(summary.ingested_transactions, 3);
+        assert_eq!(tx_store.epoch_summary(Some(1)).unwrap().ingested_slots, 0);
+        assert!(tx_store.epoch_summary(Some(3)).is_some());
+        assert!(tx_store.epoch_summary(Some(4)).is_none());
+        assert!(tx_store.epoch_summary(Some(u64::MAX)).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vote_policy() -> eyre::Result<()> {
+        let slot = |vote_policy: VotePolicy| -> eyre::Result<TransactionStore> {
+            let mut tx_store = TransactionStore::mock(StoreConfig {
+                vote_policy,
+                ..Default::default()
+            });
+            let mut data = slot_data(1, 0, Commitment::Finalized);
+            data.transactions.push(Transaction {
+                tx_type: TxType::Vote,
+                ..Transaction::mock("vote1", 1)
+            });
+            tx_store.append_batch(data, 1)?;
+            Ok(tx_store)
+        };
+        async fn count(tx_store: &TransactionStore, table: &str) -> eyre::Result<String> {
+            let sql = format!("SELECT count(1) AS n FROM {}", table);
+            tx_store.query_to_json(&sql).await
+        }
+
+        let keep = slot(VotePolicy::Keep)?;
+        assert_eq!(count(&keep, "transactions").await?, r#"[{"n":2}]"#);
+        assert!(count(&keep, "vote_transactions").await.is_err());
+
+        let drop = slot(VotePolicy::Drop)?;
+        assert_eq!(count(&drop, "transactions").await?, r#"[{"n":1}]"#);
+        assert!(count(&drop, "vote_transactions").await.is_err());
+
+        let separate = slot(VotePolicy::Separate)?;
+        assert_eq!(count(&separate, "transactions").await?, r#"[{"n":1}]"#);
+        assert_eq!(count(&separate, "vote_transactions").await?, r#"[{"n":1}]"#);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_logs() -> eyre::Result<()> {
+        use datafusion::arrow::array::Array;
+        let logs = |keep_logs: bool| -> eyre::Result<usize> {
+            let tx_store = TransactionStore::mock(StoreConfig {
+                keep_logs,
+                ..Default::default()
+            });
+            let mut data = slot_data(1, 0, Commitment::Finalized);
+            data.transactions[0].log_messages = Some(vec!["Program log: hi".to_string()]);
+            let segment = tx_store.to_segment(1, data)?;
+            let column = segment.transactions.column_by_name("log_messages").unwrap();
+            Ok(column.len() - column.null_count())
+        };
+        assert_eq!(logs(false)?, 0);
+        assert_eq!(logs(true)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscribe_transactions() -> eyre::Result<()> {
+        let mut tx_store = TransactionStore::mock(StoreConfig::default());
+        let mut appended = tx_store.subscribe_transactions();
+        tx_store.append_batch(slot_data(1, 0, Commitment::Confirmed), 1)?;
+        tx_store.append_batch(slot_data(2, 1, Commitment::Finalized), 2)?;
+        for slot in [1, 2] {
+            let batch = appended.try_recv()?;
+            assert_eq!(slot_rows(&batch)?.keys().collect::<Vec<_>>(), vec![&slot]);
+        }
+        assert!(appended.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_continuous_view() -> eyre::Result<()> {
+        let mut tx_store = TransactionStore::mock(StoreConfig {
+            commitment: Commitment::Confirmed,
+            ..Default::default()
+        });
+        let mut finalized = tx_store.subscribe_finalized();
+        for slot in [1, 2] {
+            tx_store.append_batch(slot_data(slot, slot - 1, Commitment::Finalized), slot)?;
+        }
+        let sql = "SELECT sender, count(1) AS n, sum(amount) AS volume \
+                   FROM transactions GROUP BY sender";
+        let view = tx_store.create_view("volume_by_sender", sql).await?;
+        assert!(tx_store.create_view("volume_by_sender", sql).await.is_err());
+        assert!(tx_store.create_view("Bad-Name", sql).await.is_err());
+        assert!(tx_store
+            .create_view("avg_fee", "SELECT avg(fee) FROM transactions")
+            .await
+            .is_err());
+
+        for slot in [3, 4] {
+            tx_store.append_batch(slot_data(slot, slot - 1, Commitment::Confirmed), slot)?;
+        }
+        tx_store.finalize(4, &[3, 4])?;
+        //slots 1 and 2 were already covered when the view was created
+        while let Ok((slot, transactions)) = finalized.try_recv() {
+            view.apply(slot, &transactions).await?;
+        }
+        assert_eq!(view.info().slots, 4);
+
+        let json = tx_store
+            .query_to_json("SELECT n, volume FROM volume_by_sender")
+            .await?;
+        assert_eq!(json, r#"[{"n":4,"volume":4}]"#);
+
+        //a missed slot is caught up from the store, once
+        tx_store.append_batch(slot_data(5, 4, Commitment::Finalized), 5)?;
+        let source = tx_store.finalized_transactions().await?;
+        assert_eq!(view.catch_up(source.clone()).await?, 1);
+        assert_eq!(view.catch_up(source).await?, 0);
+        //rows evicted from the store stay counted
+        tx_store.data_mut().evict_before(5)?;
+        let json = tx_store
+            .query_to_json("SELECT n, volume FROM volume_by_sender")
+            .await?;
+        assert_eq!(json, r#"[{"n":5,"volume":5}]"#);
+        assert_eq!(view.info().slots, 5);
+
+        assert!(tx_store.drop_view("volume_by_sender")?);
+        assert!(!tx_store.drop_view("volume_by_sender")?);
+        assert!(tx_store
+            .query("SELECT * FROM volume_by_sender")
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_only_queries() -> eyre::Result<()> {
+        let mut tx_store = TransactionStore::mock(StoreConfig::default());
+        tx_store.append_batch(slot_data(1, 0, Commitment::Finalized), 1)?;
+        for sql in [
+            "DROP TABLE transactions",
+            "CREATE TABLE t AS SELECT * FROM transactions",
+            "CREATE VIEW t AS SELECT * FROM transactions",
+            "SET datafusion.execution.batch_size = 1",
+        ] {
+            assert!(tx_store.query(sql).await.is_err(), "{}", sql);
+        }
+        let json = tx_store
+            .query_to_json("SELECT count(1) AS n FROM transactions")
+            .await?;
+        assert_eq!(json, r#"[{"n":1}]"#);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_paged_query() -> eyre::Result<()> {
+        use crate::page::{ndjson_stream, Order, PageParams};
+        use futures_util::TryStreamExt;
+        let mut tx_store = TransactionStore::mock(StoreConfig::default());
+        for slot in 1..=5 {
+            tx_store.append_batch(slot_data(slot, slot - 1, Commitment::Finalized), slot)?;
+        }
+        let mut page = PageParams {
+            limit: Some(2),
+            order: Order::Desc,
+            cursor: None,
+        };
+        let mut slots = vec![];
+        loop {
+            let sql = page.to_sql("transactions", "array_has(account_keys, 'a')")?;
+            let batches = tx_store.query_stream(&sql).await?;
+            let chunks: Vec<_> = ndjson_stream(batches, Some(page.limit()))
+                .try_collect()
+                .await?;
+            let body = String::from_utf8(chunks.concat())?;
+            let lines = body
+                .lines()
+                .map(serde_json::from_str)
+                .collect::<Result<Vec<serde_json::Value>, _>>()?;
+            let (rows, cursor) = match lines.split_last() {
+                Some((last, rows)) if last.get("next_cursor").is_some() => {
+                    (rows, last["next_cursor"].as_str())
+                }
+                _ => (lines.as_slice(), None),
+            };
+            assert!(rows.len() <= 2);
+            slots.extend(rows.iter().map(|tx| tx["slot"].as_u64().unwrap()));
+            match cursor {
+                Some(cursor) => page.cursor = Some(cursor.to_string()),
+                None => break,
+            }
+        }
+        assert_eq!(slots, vec![5, 4, 3, 2, 1]);
+
+        //unpaged, every row is streamed
+        let batches = tx_store
+            .query_stream("SELECT slot FROM transactions")
+            .await?;
+        let chunks: Vec<_> = ndjson_stream(batches, None).try_collect().await?;
+        assert_eq!(String::from_utf8(chunks.concat())?.lines().count(), 5);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_account_filter_paging() -> eyre::Result<()> {
+        use crate::page::{take_page, AccountFilter, Order, PageParams};
+        use datafusion::arrow::{array::AsArray, datatypes::UInt64Type};
+        use futures_util::TryStreamExt;
+        let mut tx_store = TransactionStore::mock(StoreConfig::default());
+        tx_store.epoch_schedule = EpochSchedule::custom(32, 32, false);
+        for slot in 30..=37 {
+            let mut data = slot_data(slot, slot - 1, Commitment::Finalized);
+            data.transactions[0].block_time = Some(slot as i64 * 100);
+            if slot % 3 == 0 {
+                data.transactions[0].err = Some("fail".to_string());
+            }
+            tx_store.append_batch(data, slot)?;
+        }
+        //epoch 1 starts at slot 32, slots 33 and 36 failed, slot 37 is past `to`
+        let filter = AccountFilter {
+            from: Some("3100".to_string()),
+            to: Some("1970-01-01T01:01:40Z".to_string()),
+            success: Some(true),
+            epoch: Some(1),
+        };
+        let predicate = filter.predicate("a")?;
+        let mut page = PageParams {
+            limit: Some(2),
+            order: Order::Desc,
+            cursor: None,
+        };
+        let (mut slots, mut pages) = (vec![], 0);
+        loop {
+            let sql = page.to_sql("transactions", &predicate)?;
+            //the account lookup still reaches the scan next to the other filters
+            let plan = tx_store.dataframe(&sql).await?.into_optimized_plan()?;
+            let plan = plan.display_indent().to_string();
+            let scan = plan.lines().find(|l| l.contains("TableScan")).unwrap();
+            assert!(scan.contains("array_has"), "{}", plan);
+
+            let rows = tx_store.query_stream(&sql).await?;
+            let (rows, cursor) = take_page(rows, page.limit()).await?;
+            pages += 1;
+            let rows: Vec<RecordBatch> = rows.try_collect().await?;
+            for batch in &rows {
+                let column = batch.column_by_name("slot").unwrap();
+                slots.extend(column.as_primitive::<UInt64Type>().values().iter().copied());
+            }
+            match cursor {
+                Some(cursor) => page.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(slots, vec![35, 34, 32]);
+        assert_eq!(pages, 2);
+
+        assert!(AccountFilter {
+            from: Some("yesterday".to_string()),
+            ..Default::default()
+        }
+        .predicate("a")
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_retention_eviction() -> eyre::Result<()> {
+        let dir = std::env::temp_dir().join(format!("solagg_evict_{}", std::process::id()));
+        let mut tx_store = TransactionStore::mock(StoreConfig {
+            retention: Retention {
+                slots: Some(4),
+                evict_to: Some(dir.clone()),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        for slot in 1..=10 {
+            tx_store.current_slot = slot;
+            tx_store.append_batch(slot_data(slot, slot - 1, Commitment::Finalized), slot)?;
+            if let Some(task) = tx_store.evicting.take() {
+                task.await?;
+            }
+        }
+        assert_eq!(tx_store.size(), 4);
+        assert!(!tx_store.has_slot(6));
+        assert!(tx_store.has_slot(7));
+        assert!(tx_store.transaction_by_signature("sig6").is_none());
+        assert!(dir.join("transactions").join("6-6.parquet").exists());
+
+        let report = tx_store.memory_report();
+        assert_eq!(report.recent_slots, 4);
+        assert_eq!(report.transaction_rows, 4);
+        assert!(report.transactions_bytes > 0);
+        assert_eq!(
+            report.total_bytes,
+            report.transactions_bytes + report.blocks_bytes + report.rewards_bytes
+        );
+        assert_eq!(report.total_bytes, tx_store.data().bytes());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_retention_failed_write_kept() -> eyre::Result<()> {
+        //NOTE a file in place of the directory fails every write
+        let dir = std::env::temp_dir().join(format!("solagg_evict_fail_{}", std::process::id()));
+        std::fs::write(&dir, "")?;
+        let mut tx_store = TransactionStore::mock(StoreConfig {
+            retention: Retention {
+                slots: Some(4),
+                evict_to: Some(dir.clone()),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        for slot in 1..=10 {
+            tx_store.current_slot = slot;
+            tx_store.append_batch(slot_data(slot, slot - 1, Commitment::Finalized), slot)?;
+            if let Some(task) = tx_store.evicting.take() {
+                task.await?;
+            }
+        }
+        assert_eq!(tx_store.size(), 10);
+        assert!(tx_store.has_slot(1));
+        assert!(tx_store.transaction_by_signature("sig1").is_some());
+
+        //NOTE the next round retries the rows kept
+        std::fs::remove_file(&dir)?;
+        tx_store.current_slot = 11;
+        tx_store.append_batch(slot_data(11, 10, Commitment::Finalized), 11)?;
+        if let Some(task) = tx_store.evicting.take() {
+            task.await?;
+        }
+        assert_eq!(tx_store.size(), 4);
+        assert!(!tx_store.has_slot(7));
+        assert!(tx_store.has_slot(8));
+        assert!(dir.join("transactions").join("1-1.parquet").exists());
+        assert_eq!(
+            tx_store.memory_report().total_bytes,
+            tx_store.data().bytes()
+        );
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn test_transaction_store_query() -> eyre::Result<()> {
         env_logger::Builder::new()
@@ -132,12 +1208,11 @@ mod unit_tests {
             .filter_module("solagg::store", log::LevelFilter::Trace)
             .init();
         let mocked = true;
-        let tx_store = TransactionStore::new(mocked)?;
+        let tx_store = TransactionStore::new(mocked, StoreConfig::default())?;
         println!("tx_store.size:\n{:#?}", tx_store.size());
         assert!(tx_store.size() > 0);
-        let table_name = "transactions";
         let sql = "SELECT count(1) FROM transactions";
-        let batches = tx_store.query(sql, table_name).await?;
+        let batches = tx_store.query(sql).await?;
         let value = batches[0]
             .column(0)
             .as_any()
@@ -146,9 +1221,26 @@ mod unit_tests {
             .value(0);
         assert_eq!(tx_store.size(), value as usize);
         //check json output
-        let json = tx_store.query_to_json(sql, table_name).await?;
+        let json = tx_store.query_to_json(sql).await?;
         // println!("json:\n{:#?}", json);
         assert!(json.contains("count(Int64(1))"));
+        //blocks and rewards are registered alongside transactions
+        let sql = "SELECT count(1) FROM blocks";
+        let batches = tx_store.query(sql).await?;
+        let value = batches[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<datafusion::arrow::array::Int64Array>()
+            .unwrap()
+            .value(0);
+        let slots: usize = tx_store
+            .data()
+            .in_range(0, u64::MAX)
+            .map(|s| s.blocks.num_rows())
+            .sum();
+        assert_eq!(slots, value as usize);
+        let sql = "SELECT count(1) FROM rewards";
+        assert!(!tx_store.query(sql).await?.is_empty());
         Ok(())
     }
 
@@ -159,10 +1251,9 @@ mod unit_tests {
         //     .filter_module("solagg::store", log::LevelFilter::Trace)
         //     .init();
         let mocked = true;
-        let tx_store = TransactionStore::new(mocked)?;
+        let tx_store = TransactionStore::new(mocked, StoreConfig::default())?;
         println!("tx_store.size:\n{:#?}", tx_store.size());
         assert!(tx_store.size() > 0);
-        let table_name = "transactions";
         //FIXME should check when day crosses
         let today = Utc::now().date_naive().format("%Y-%m-%d").to_string();
         println!("today: {}", today);
@@ -170,11 +1261,11 @@ mod unit_tests {
             "SELECT * FROM transactions WHERE cast(block_time as DATE) = '{}'",
             today
         );
-        let batches = tx_store.query(&sql, table_name).await?;
+        let batches = tx_store.query(&sql).await?;
         // println!("batches: {:#?}", batches);
         assert_eq!(tx_store.size(), batches[0].num_rows());
         //check json output
-        let json = tx_store.query_to_json(&sql, table_name).await?;
+        let json = tx_store.query_to_json(&sql).await?;
         // println!("json:\n{:#?}", json);
         assert!(json.contains("block_time"));
         Ok(())
@@ -1,36 +1,89 @@
-use futures_util::{SinkExt, StreamExt};
-use log::trace;
+use datafusion::{
+    arrow::{array::RecordBatch, datatypes::SchemaRef},
+    execution::SendableRecordBatchStream,
+    physical_plan::memory::MemoryStream,
+};
+use futures_util::{future::join_all, SinkExt, StreamExt};
+use log::{debug, trace, warn};
 use serde_json::json;
-use std::sync::Arc;
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
 use tokio::sync::{
+    broadcast::{self, error::RecvError},
     mpsc::{self, UnboundedReceiver, UnboundedSender},
     RwLock,
 };
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 
+pub use crate::{
+    epoch::EpochSummary,
+    filter::IngestFilter,
+    retention::{MemoryReport, Retention},
+    store::{StoreConfig, StoreEvent, VotePolicy},
+    types::Commitment,
+};
 use crate::{
     fetch::{SolFetcher, SOL_RPC_URL},
-    store::TransactionStore,
+    store::{batches_to_json, TransactionStore},
+    subscribe::{updates, TxSubscription, TxUpdate},
+    types::SlotData,
+    view::ViewInfo,
+    webhook::{spawn_dispatcher, ThreadSafeWebhooks, WebhookRegistry},
 };
 
+/// Fetch attempts of a processed slot at `confirmed`, one per slot notification
+const CONFIRM_ATTEMPTS: u32 = 8;
 const SOL_RPC_WS: &str =
     "wss://devnet.helius-rpc.com/?api-key=12ce24fe-c92e-42c0-8f29-b9fdb4757c49";
 
 pub struct SolAggStreamer {
     pub(crate) tx_store: TransactionStore,
+    pub(crate) webhooks: ThreadSafeWebhooks,
 }
 
 pub type TheadSafeStreamer = Arc<RwLock<SolAggStreamer>>;
 
+/// A slot notification: the slot the node is processing and the latest root
+#[derive(Debug, Clone, Copy)]
+struct SlotUpdate {
+    slot: u64,
+    root: u64,
+}
+
 impl SolAggStreamer {
-    pub fn new_arc(mocked: bool) -> eyre::Result<TheadSafeStreamer> {
-        Ok(Arc::new(RwLock::new(Self {
-            tx_store: TransactionStore::new(mocked)?,
-        })))
+    pub fn new_arc(mocked: bool, config: StoreConfig) -> eyre::Result<TheadSafeStreamer> {
+        Ok(Self::with_store(TransactionStore::new(mocked, config)?))
+    }
+
+    pub(crate) fn with_store(tx_store: TransactionStore) -> TheadSafeStreamer {
+        Arc::new(RwLock::new(Self {
+            tx_store,
+            webhooks: Default::default(),
+        }))
+    }
+
+    /// Persists the webhook registry under `dir`, loading the webhooks already there
+    pub fn load_webhooks(&mut self, dir: PathBuf) -> eyre::Result<()> {
+        self.webhooks = Arc::new(RwLock::new(WebhookRegistry::load(dir)?));
+        Ok(())
+    }
+
+    /// Subscribes to store events, e.g. rollbacks of slots found on an abandoned fork
+    pub fn subscribe_events(&self) -> broadcast::Receiver<StoreEvent> {
+        self.tx_store.subscribe()
     }
 }
 
 pub async fn start_streamer(streamer: TheadSafeStreamer) -> eyre::Result<()> {
+    let (compactor, dispatcher) = {
+        let s = streamer.read().await;
+        let finalized = s.tx_store.subscribe_finalized();
+        (
+            s.tx_store.start_compactor(),
+            spawn_dispatcher(finalized, s.webhooks.clone()),
+        )
+    };
+    let finalized = streamer.read().await.tx_store.subscribe_finalized();
+    let maintainer = tokio::spawn(maintain_views(streamer.clone(), finalized));
     let (tx, rx) = mpsc::unbounded_channel();
     let fut_watch_sol = tokio::spawn(async move { watch_sol_rpc_ws(tx).await });
     let fut_proc_sol = tokio::spawn(async move { process_sol_notifications(streamer, rx).await });
@@ -43,57 +96,381 @@ pub async fn start_streamer(streamer: TheadSafeStreamer) -> eyre::Result<()> {
             log::error!("streamer failed; error = {}", err);
         }
     }
+    compactor.abort();
+    dispatcher.abort();
+    maintainer.abort();
     Ok(())
 }
 
-pub(crate) async fn query_to_json(
+/// Folds each finalized slot into the continuous views, recomputing them from the
+/// store when slots were missed
+async fn maintain_views(
+    streamer: TheadSafeStreamer,
+    mut finalized: broadcast::Receiver<(u64, RecordBatch)>,
+) {
+    loop {
+        match finalized.recv().await {
+            Ok((slot, transactions)) => {
+                //NOTE the views are applied outside the streamer lock, not to hold ingest
+                let views = streamer.read().await.tx_store.views();
+                for view in views {
+                    if let Err(e) = view.apply(slot, &transactions).await {
+                        warn!(
+                            "failed to update view {} at slot {}: {}",
+                            view.name, slot, e
+                        );
+                    }
+                }
+            }
+            Err(RecvError::Lagged(slots)) => {
+                //NOTE the missed slots still stored are folded in from the store, the
+                //     ones evicted meanwhile are lost to the views
+                let (views, source) = {
+                    let s = streamer.read().await;
+                    (
+                        s.tx_store.views(),
+                        s.tx_store.finalized_transactions().await,
+                    )
+                };
+                let source = match source {
+                    Ok(source) => source,
+                    Err(e) => {
+                        warn!("failed to catch up views: {}", e);
+                        continue;
+                    }
+                };
+                for view in views {
+                    match view.catch_up(source.clone()).await {
+                        Ok(caught_up) => debug!(
+                            "--- view {} missed {} slots, caught up {} from the store",
+                            view.name, slots, caught_up
+                        ),
+                        Err(e) => warn!("failed to catch up view {}: {}", view.name, e),
+                    }
+                }
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+pub(crate) async fn query_to_json(streamer: TheadSafeStreamer, sql: &str) -> eyre::Result<String> {
+    Ok(streamer.read().await.tx_store.query_to_json(sql).await?)
+}
+
+/// Rows of `sql`, produced as the stream is polled.
+///NOTE the streamer lock is only held while planning, not while the rows are sent
+pub(crate) async fn query_stream(
+    streamer: TheadSafeStreamer,
+    sql: &str,
+) -> eyre::Result<SendableRecordBatchStream> {
+    streamer.read().await.tx_store.query_stream(sql).await
+}
+
+/// The schema of the rows of `sql`, without running it
+pub(crate) async fn query_schema(
     streamer: TheadSafeStreamer,
     sql: &str,
-    table_name: &str,
+) -> eyre::Result<SchemaRef> {
+    streamer.read().await.tx_store.query_schema(sql).await
+}
+
+/// The queryable tables with their schemas
+pub(crate) async fn tables(streamer: TheadSafeStreamer) -> eyre::Result<Vec<(String, SchemaRef)>> {
+    streamer.read().await.tx_store.tables().await
+}
+
+/// Looks a transaction up by signature through the store's index, as a JSON array
+pub(crate) async fn transaction_to_json(
+    streamer: TheadSafeStreamer,
+    signature: &str,
 ) -> eyre::Result<String> {
-    Ok(streamer
+    let tx_store = &streamer.read().await.tx_store;
+    let batches: Vec<_> = tx_store
+        .transaction_by_signature(signature)
+        .into_iter()
+        .collect();
+    batches_to_json(&batches)
+}
+
+/// Looks a transaction up by signature through the store's index, as a stream of at
+/// most one row
+pub(crate) async fn transaction_stream(
+    streamer: TheadSafeStreamer,
+    signature: &str,
+) -> eyre::Result<SendableRecordBatchStream> {
+    let tx_store = &streamer.read().await.tx_store;
+    let schema = tx_store.schema("transactions").await?;
+    let batches = tx_store
+        .transaction_by_signature(signature)
+        .into_iter()
+        .collect();
+    Ok(Box::pin(MemoryStream::try_new(batches, schema, None)?))
+}
+
+/// Live updates of the transactions matching `subscription`, from now on
+pub(crate) async fn subscribe_transactions(
+    streamer: TheadSafeStreamer,
+    subscription: TxSubscription,
+) -> impl futures_util::Stream<Item = TxUpdate> + Send {
+    let rx = streamer.read().await.tx_store.subscribe_transactions();
+    updates(rx, subscription)
+}
+
+/// Registers a continuous view, see `TransactionStore::create_view`
+pub(crate) async fn create_view(
+    streamer: TheadSafeStreamer,
+    name: &str,
+    sql: &str,
+) -> eyre::Result<ViewInfo> {
+    let view = streamer
         .read()
         .await
         .tx_store
-        .query_to_json(sql, table_name)
-        .await?)
+        .create_view(name, sql)
+        .await?;
+    Ok(view.info())
+}
+
+/// Returns whether the view existed
+pub(crate) async fn drop_view(streamer: TheadSafeStreamer, name: &str) -> eyre::Result<bool> {
+    streamer.read().await.tx_store.drop_view(name)
+}
+
+pub(crate) async fn views(streamer: TheadSafeStreamer) -> Vec<ViewInfo> {
+    let views = streamer.read().await.tx_store.views();
+    views.iter().map(|v| v.info()).collect()
+}
+
+/// The current rows of a continuous view, none if there is no such view
+pub(crate) async fn view_stream(
+    streamer: TheadSafeStreamer,
+    name: &str,
+) -> eyre::Result<Option<SendableRecordBatchStream>> {
+    let Some(view) = streamer.read().await.tx_store.view(name) else {
+        return Ok(None);
+    };
+    let stream = MemoryStream::try_new(vec![view.rows()], view.schema(), None)?;
+    Ok(Some(Box::pin(stream)))
+}
+
+/// Memory held by the store's Arrow buffers
+pub(crate) async fn memory_report(streamer: TheadSafeStreamer) -> MemoryReport {
+    streamer.read().await.tx_store.memory_report()
+}
+
+/// Summary of an epoch, the current one if `epoch` is none
+pub(crate) async fn epoch_summary(
+    streamer: TheadSafeStreamer,
+    epoch: Option<u64>,
+) -> Option<EpochSummary> {
+    streamer.read().await.tx_store.epoch_summary(epoch)
 }
 
 async fn process_sol_notifications(
     streamer: TheadSafeStreamer,
-    mut rx: UnboundedReceiver<u64>,
+    rx: UnboundedReceiver<SlotUpdate>,
 ) -> eyre::Result<()> {
-    let sol_fetcher = SolFetcher::new(SOL_RPC_URL);
-    while let Some(slot) = rx.recv().await {
-        trace!("--- Received slot number: {}", slot);
-        let batch = sol_fetcher.fetch_transactions_as_batch(slot).await?;
+    let commitment = streamer.read().await.tx_store.config.commitment;
+    match commitment {
+        Commitment::Finalized => process_finalized_slots(streamer, rx).await,
+        Commitment::Confirmed => process_confirmed_slots(streamer, rx).await,
+    }
+}
+
+/// Runs a blocking RPC call off the async workers
+async fn rpc<T: Send + 'static>(
+    fetcher: &Arc<SolFetcher>,
+    call: impl FnOnce(&SolFetcher) -> eyre::Result<T> + Send + 'static,
+) -> eyre::Result<T> {
+    let fetcher = fetcher.clone();
+    tokio::task::spawn_blocking(move || call(&fetcher)).await?
+}
+
+/// Fetches the data of `slots` concurrently, one single shot RPC call each
+async fn fetch_slots(fetcher: &Arc<SolFetcher>, slots: &[u64]) -> Vec<eyre::Result<SlotData>> {
+    join_all(slots.iter().map(|s| {
+        let s = *s;
+        rpc(fetcher, move |f| f.fetch_slot_data_sync(s))
+    }))
+    .await
+}
+
+async fn process_finalized_slots(
+    streamer: TheadSafeStreamer,
+    mut rx: UnboundedReceiver<SlotUpdate>,
+) -> eyre::Result<()> {
+    let sol_fetcher = Arc::new(SolFetcher::new(SOL_RPC_URL));
+    while let Some(SlotUpdate { root, .. }) = rx.recv().await {
+        trace!("--- Received root number: {}", root);
+        //NOTE the same root is notified for several processed slots
+        let last = streamer.read().await.tx_store.current_slot;
+        if root <= last {
+            continue;
+        }
+        //NOTE the root may jump several slots at once, every finalized block since the
+        //     last stored slot is fetched; on errors the stored slots are kept and the
+        //     next root retries the rest
+        let slots = match rpc(&sol_fetcher, move |f| {
+            f.get_finalized_blocks(last + 1, root)
+        })
+        .await
+        {
+            Ok(slots) => slots,
+            Err(e) => {
+                warn!(
+                    "failed to list finalized slots {}..={}; error = {}",
+                    last + 1,
+                    root,
+                    e
+                );
+                continue;
+            }
+        };
+        let fetched = fetch_slots(&sol_fetcher, &slots).await;
         //NOTE minizie the lock scope
         let tx_store = &mut streamer.write().await.tx_store;
-        //NOTE: corner case#1
-        //      may have init slot gap in a very small possibility, just check once
-        let cur_slot = tx_store.current_slot;
-        if tx_store.init_slot == cur_slot {
-            if slot > (cur_slot + 1) {
-                for slot in (cur_slot + 1)..slot {
-                    log::debug!(
-                        "--- init slot gap backfill, init_slot: {}, insert slot: {}",
-                        cur_slot,
-                        slot
-                    );
-                    let batch = sol_fetcher.fetch_transactions_as_batch(slot).await?;
-                    tx_store.append_batch(batch, slot);
+        let stored =
+            slots
+                .into_iter()
+                .zip(fetched)
+                .try_for_each(|(slot, data)| -> eyre::Result<()> {
+                    tx_store.append_batch(data?, slot)?;
+                    tx_store.current_slot = slot;
+                    Ok(())
+                });
+        match stored {
+            Ok(()) => tx_store.current_slot = root,
+            Err(e) => warn!(
+                "failed to store finalized slots up to {}; error = {}",
+                root, e
+            ),
+        }
+    }
+    trace!("??? process_finalized_slots exited.");
+    Ok(())
+}
+
+///NOTE confirmed slots are ingested as soon as they are notified, then reconciled
+///     against the finalized chain whenever the root advances
+async fn process_confirmed_slots(
+    streamer: TheadSafeStreamer,
+    mut rx: UnboundedReceiver<SlotUpdate>,
+) -> eyre::Result<()> {
+    let sol_fetcher = Arc::new(SolFetcher::new_with_commitment(
+        SOL_RPC_URL,
+        Commitment::Confirmed,
+    ));
+    let finalized_fetcher = Arc::new(SolFetcher::new(SOL_RPC_URL));
+    //NOTE slot notifications are for processed slots, which only become fetchable at
+    //     `confirmed` a few slots later, so they are probed again on the next notifications
+    let mut unconfirmed: BTreeMap<u64, u32> = BTreeMap::new();
+    while let Some(SlotUpdate { slot, root }) = rx.recv().await {
+        trace!("--- Received slot number: {}, root: {}", slot, root);
+        let last_finalized = streamer.read().await.tx_store.last_finalized_slot;
+        if slot > last_finalized {
+            unconfirmed.insert(slot, 0);
+        }
+        //NOTE slots up to the root are left to the reconciliation below
+        unconfirmed.retain(|s, _| *s > last_finalized.max(root));
+        let probed: Vec<u64> = unconfirmed.keys().copied().collect();
+        let fetched = fetch_slots(&sol_fetcher, &probed).await;
+        let mut confirmed = vec![];
+        for (s, data) in probed.into_iter().zip(fetched) {
+            match data {
+                Ok(data) => confirmed.push((s, data)),
+                Err(e) => {
+                    if let Some(attempts) = unconfirmed.get_mut(&s) {
+                        *attempts += 1;
+                    }
+                    trace!("--- slot not confirmed yet: {}, {}", s, e);
+                }
+            }
+        }
+        //NOTE a slot skipped or never confirmed is given up on, the reconciliation
+        //     backfills it if it gets finalized after all
+        unconfirmed.retain(|s, attempts| {
+            let fetched = confirmed.iter().any(|(c, _)| c == s);
+            if !fetched && *attempts >= CONFIRM_ATTEMPTS {
+                debug!("--- skip unconfirmed slot: {}", s);
+            }
+            !fetched && *attempts < CONFIRM_ATTEMPTS
+        });
+        if !confirmed.is_empty() {
+            let tx_store = &mut streamer.write().await.tx_store;
+            for (s, data) in confirmed {
+                tx_store.current_slot = tx_store.current_slot.max(s);
+                //NOTE a slot failing here is backfilled once finalized
+                if let Err(e) = tx_store.append_batch(data, s) {
+                    warn!("failed to store confirmed slot {}; error = {}", s, e);
                 }
             }
         }
-        tx_store.current_slot = slot;
+        if root <= last_finalized {
+            continue;
+        }
 
-        tx_store.append_batch(batch, slot);
+        //NOTE on errors `last_finalized_slot` is left as is, so the next root retries
+        let finalized = match rpc(&finalized_fetcher, move |f| {
+            f.get_finalized_blocks(last_finalized + 1, root)
+        })
+        .await
+        {
+            Ok(finalized) => finalized,
+            Err(e) => {
+                warn!(
+                    "failed to list finalized slots up to {}; error = {}",
+                    root, e
+                );
+                continue;
+            }
+        };
+        let missing: Vec<u64> = {
+            let tx_store = &streamer.read().await.tx_store;
+            let pending = tx_store.pending_slots(root);
+            finalized
+                .iter()
+                .copied()
+                .filter(|s| !pending.contains(s) && !tx_store.has_slot(*s))
+                .collect()
+        };
+        let fetched = fetch_slots(&finalized_fetcher, &missing).await;
+        let backfill = missing
+            .into_iter()
+            .zip(fetched)
+            .map(|(s, data)| {
+                debug!("--- finalized slot backfill: {}", s);
+                Ok((s, data?))
+            })
+            .collect::<eyre::Result<Vec<_>>>();
+        let backfill = match backfill {
+            Ok(backfill) => backfill,
+            Err(e) => {
+                warn!(
+                    "failed to backfill finalized slots up to {}; error = {}",
+                    root, e
+                );
+                continue;
+            }
+        };
+        let tx_store = &mut streamer.write().await.tx_store;
+        let orphaned = backfill
+            .into_iter()
+            .try_for_each(|(s, data)| tx_store.append_batch(data, s))
+            .and_then(|_| tx_store.finalize(root, &finalized));
+        match orphaned {
+            Ok(orphaned) if !orphaned.is_empty() => {
+                debug!("--- dropped orphaned slots: {:?}", orphaned);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("failed to finalize slots up to {}; error = {}", root, e),
+        }
     }
-    trace!("??? process_sol_notifications exited.");
+    trace!("??? process_confirmed_slots exited.");
     Ok(())
 }
 
-async fn watch_sol_rpc_ws(tx: UnboundedSender<u64>) -> eyre::Result<()> {
+async fn watch_sol_rpc_ws(tx: UnboundedSender<SlotUpdate>) -> eyre::Result<()> {
     let (ws_stream, _) = connect_async(SOL_RPC_WS).await?;
     let (mut write, mut read) = ws_stream.split();
 
@@ -116,8 +493,11 @@ async fn watch_sol_rpc_ws(tx: UnboundedSender<u64>) -> eyre::Result<()> {
                     match method.as_str().unwrap() {
                         "slotNotification" => {
                             trace!("New slot: {}", json);
-                            if let Some(slot) = json["params"]["result"]["root"].as_u64() {
-                                if let Err(e) = tx.send(slot) {
+                            let result = &json["params"]["result"];
+                            if let (Some(slot), Some(root)) =
+                                (result["slot"].as_u64(), result["root"].as_u64())
+                            {
+                                if let Err(e) = tx.send(SlotUpdate { slot, root }) {
                                     eyre::bail!(
                                         "Failed to send slot number, receiver dropped: {}",
                                         e
@@ -164,12 +544,11 @@ mod unit_tests {
             .filter_module("solagg", log::LevelFilter::Trace)
             .init();
 
-        let streamer = SolAggStreamer::new_arc(true)?;
+        let streamer = SolAggStreamer::new_arc(true, StoreConfig::default())?;
         let s = streamer.clone();
         tokio::spawn(async move { start_streamer(s).await });
-        let table_name = "transactions";
         let sql = "SELECT count(1) FROM transactions";
-        let json = query_to_json(streamer, sql, table_name).await?;
+        let json = query_to_json(streamer, sql).await?;
         assert!(json.contains("count(Int64(1))"));
         Ok(())
     }
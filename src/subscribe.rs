@@ -0,0 +1,250 @@
+use crate::page::is_base58;
+use datafusion::arrow::{
+    array::{Array, AsArray, BooleanArray, GenericListArray, RecordBatch, StringArray},
+    compute::{cast, filter_record_batch},
+    datatypes::{DataType, Int64Type},
+};
+use futures_util::Stream;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Filters of a live transaction subscription, every one set has to match
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct TxSubscription {
+    /// an account in any key position
+    pub(crate) account: Option<String>,
+    /// a program invoked by the transaction
+    pub(crate) program: Option<String>,
+    /// minimum absolute balance change of the fee payer, in lamports
+    pub(crate) min_amount: Option<u64>,
+    /// an account whose balance the transaction changed: the fee payer, or the receiver
+    /// of a successful system transfer
+    pub(crate) balance_change: Option<String>,
+}
+
+/// What is pushed to a subscriber
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum TxUpdate {
+    /// a matching transaction, as a JSON object
+    Row(String),
+    /// the subscriber fell behind and missed the rows of this many slots
+    Lagged(u64),
+}
+
+impl TxUpdate {
+    pub(crate) fn to_json(&self) -> String {
+        match self {
+            Self::Row(row) => row.clone(),
+            Self::Lagged(slots) => json!({ "lagged_slots": slots }).to_string(),
+        }
+    }
+}
+
+impl TxSubscription {
+    pub(crate) fn validate(&self) -> eyre::Result<()> {
+        for key in [&self.account, &self.program, &self.balance_change]
+            .into_iter()
+            .flatten()
+        {
+            if !is_base58(key) {
+                eyre::bail!("invalid pubkey: {}", key);
+            }
+        }
+        Ok(())
+    }
+
+    /// The rows of a slot's `transactions` batch the subscription matches, as JSON objects
+    pub(crate) fn matching_rows(&self, batch: &RecordBatch) -> eyre::Result<Vec<String>> {
+        let list = |name: &str| {
+            batch
+                .column_by_name(name)
+                .and_then(|c| c.as_list_opt::<i32>())
+                .ok_or_else(|| eyre::eyre!("no {} column in batch", name))
+        };
+        let (keys, programs) = (list("account_keys")?, list("program_ids")?);
+        let amounts = batch
+            .column_by_name("amount")
+            .and_then(|c| c.as_primitive_opt::<Int64Type>())
+            .ok_or_else(|| eyre::eyre!("no amount column in batch"))?;
+        //NOTE pubkey columns are dictionary encoded once stored
+        let strings = |name: &str| -> eyre::Result<StringArray> {
+            let column = batch
+                .column_by_name(name)
+                .ok_or_else(|| eyre::eyre!("no {} column in batch", name))?;
+            Ok(cast(column, &DataType::Utf8)?.as_string::<i32>().clone())
+        };
+        let (senders, receivers) = (strings("sender")?, strings("receiver")?);
+        let (tx_types, errs) = (strings("tx_type")?, strings("err")?);
+        let mask: BooleanArray = (0..batch.num_rows())
+            .map(|row| {
+                let matches = self
+                    .account
+                    .as_deref()
+                    .map_or(true, |a| list_contains(keys, row, a))
+                    && self
+                        .program
+                        .as_deref()
+                        .map_or(true, |p| list_contains(programs, row, p))
+                    && self
+                        .min_amount
+                        .map_or(true, |min| amounts.value(row).unsigned_abs() >= min)
+                    && self.balance_change.as_deref().map_or(true, |a| {
+                        senders.value(row) == a
+                            || (receivers.value(row) == a
+                                && tx_types.value(row) == "system_transfer"
+                                && errs.is_null(row))
+                    });
+                Some(matches)
+            })
+            .collect();
+        let rows = filter_record_batch(batch, &mask)?;
+        if rows.num_rows() == 0 {
+            return Ok(vec![]);
+        }
+        let mut writer = arrow_json::LineDelimitedWriter::new(Vec::new());
+        writer.write(&rows)?;
+        writer.finish()?;
+        Ok(String::from_utf8(writer.into_inner())?
+            .lines()
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+fn list_contains(list: &GenericListArray<i32>, row: usize, value: &str) -> bool {
+    if list.is_null(row) {
+        return false;
+    }
+    let values = list.value(row);
+    values
+        .as_string_opt::<i32>()
+        .is_some_and(|v| v.iter().any(|k| k == Some(value)))
+}
+
+/// The matching rows of every batch `rx` receives, one update per row
+pub(crate) fn updates(
+    rx: broadcast::Receiver<RecordBatch>,
+    subscription: TxSubscription,
+) -> impl Stream<Item = TxUpdate> + Send {
+    let state = (rx, subscription, VecDeque::new());
+    futures_util::stream::unfold(state, |(mut rx, subscription, mut pending)| async move {
+        loop {
+            if let Some(row) = pending.pop_front() {
+                return Some((TxUpdate::Row(row), (rx, subscription, pending)));
+            }
+            match rx.recv().await {
+                Ok(batch) => match subscription.matching_rows(&batch) {
+                    Ok(rows) => pending.extend(rows),
+                    Err(e) => warn!("failed to match subscription rows: {}", e),
+                },
+                Err(RecvError::Lagged(slots)) => {
+                    return Some((TxUpdate::Lagged(slots), (rx, subscription, pending)))
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::types::{to_record_batch, Transaction, TxType};
+    use futures_util::StreamExt;
+
+    fn batch(rows: &[(&str, &[&str], &[&str], i64)]) -> RecordBatch {
+        let txs: Vec<Transaction> = rows
+            .iter()
+            .map(|(signature, accounts, programs, amount)| Transaction {
+                sender: accounts[0].to_string(),
+                receiver: accounts[1].to_string(),
+                amount: *amount,
+                num_instructions: programs.len() as u32,
+                account_keys: accounts.iter().map(|a| a.to_string()).collect(),
+                program_ids: programs.iter().map(|p| p.to_string()).collect(),
+                ..Transaction::mock(signature, 1)
+            })
+            .collect();
+        to_record_batch(&txs, Transaction::get_arrow_scheme()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_subscription_updates() -> eyre::Result<()> {
+        let (tx, rx) = broadcast::channel(1);
+        let subscription = TxSubscription {
+            account: Some("B".to_string()),
+            program: None,
+            min_amount: Some(10),
+            balance_change: None,
+        };
+        assert!(subscription.validate().is_ok());
+        let mut updates = Box::pin(updates(rx, subscription));
+
+        tx.send(batch(&[
+            ("s1", &["A", "B"], &["P1"], -20),
+            ("s2", &["A", "C"], &["P1"], -20),
+            ("s3", &["A", "B"], &["P2"], 5),
+        ]))?;
+        let Some(TxUpdate::Row(row)) = updates.next().await else {
+            eyre::bail!("no row pushed");
+        };
+        let row: serde_json::Value = serde_json::from_str(&row)?;
+        assert_eq!(row["signature"], "s1");
+
+        //the second batch overwrites the first unread one of a one slot channel
+        tx.send(batch(&[("s4", &["B", "C"], &["P1"], 10)]))?;
+        tx.send(batch(&[("s5", &["B", "C"], &["P1"], 10)]))?;
+        assert_eq!(updates.next().await, Some(TxUpdate::Lagged(1)));
+        let Some(TxUpdate::Row(row)) = updates.next().await else {
+            eyre::bail!("no row pushed");
+        };
+        assert!(row.contains("\"s5\""));
+
+        let invalid = TxSubscription {
+            program: Some("0x".to_string()),
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_balance_change() -> eyre::Result<()> {
+        let tx = |signature: &str, sender: &str, tx_type: TxType, err: Option<&str>| Transaction {
+            tx_type,
+            err: err.map(str::to_string),
+            sender: sender.to_string(),
+            account_keys: vec![sender.to_string(), "B".to_string()],
+            ..Transaction::mock(signature, 1)
+        };
+        let txs = [
+            tx("s1", "A", TxType::SystemTransfer, None),
+            tx("s2", "A", TxType::SystemTransfer, Some("fail")),
+            tx("s3", "A", TxType::ProgramCall, None),
+            tx("s4", "B", TxType::ProgramCall, None),
+        ];
+        let batch = to_record_batch(&txs, Transaction::get_arrow_scheme())?;
+        let subscription = TxSubscription {
+            balance_change: Some("B".to_string()),
+            ..Default::default()
+        };
+        let signatures = |batch: &RecordBatch| -> eyre::Result<Vec<String>> {
+            subscription
+                .matching_rows(batch)?
+                .iter()
+                .map(|row| {
+                    let row: serde_json::Value = serde_json::from_str(row)?;
+                    Ok(row["signature"].as_str().unwrap_or_default().to_string())
+                })
+                .collect()
+        };
+        assert_eq!(signatures(&batch)?, vec!["s1", "s4"]);
+        //as stored, with dictionary encoded pubkeys
+        let stored = crate::compact::encode_pubkeys(&batch)?;
+        assert_eq!(signatures(&stored)?, vec!["s1", "s4"]);
+        Ok(())
+    }
+}
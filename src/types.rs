@@ -1,17 +1,76 @@
-use datafusion::arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+use datafusion::arrow::{
+    array::RecordBatch,
+    datatypes::{DataType, Field, Schema, TimeUnit},
+    json::ReaderBuilder,
+};
 use serde::{Deserialize, Serialize};
-use solana_transaction_status::EncodedTransactionWithStatusMeta;
+use solana_sdk::{
+    commitment_config::CommitmentConfig, compute_budget, instruction::InstructionError,
+    message::VersionedMessage, pubkey, pubkey::Pubkey, reward_type::RewardType, stake,
+    system_program, transaction::TransactionError, vote,
+};
+use solana_transaction_status::{
+    EncodedTransactionWithStatusMeta, UiConfirmedBlock, UiLoadedAddresses,
+};
+use std::sync::Arc;
+
+const TOKEN_PROGRAM_ID: Pubkey = pubkey!("TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA");
+const TOKEN_2022_PROGRAM_ID: Pubkey = pubkey!("TokenzQdBNbLqP5VEhdkAS14EPFeY8kyf9M9xXD4jnLF");
+const ASSOCIATED_TOKEN_PROGRAM_ID: Pubkey = pubkey!("ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL");
+
+/// The commitment level a row was ingested at
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Commitment {
+    /// ingest optimistically confirmed slots, reconciled once they are finalized
+    Confirmed,
+    #[default]
+    Finalized,
+}
+
+impl Commitment {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Commitment::Confirmed => "confirmed",
+            Commitment::Finalized => "finalized",
+        }
+    }
+
+    pub(crate) fn config(&self) -> CommitmentConfig {
+        match self {
+            Commitment::Confirmed => CommitmentConfig::confirmed(),
+            Commitment::Finalized => CommitmentConfig::finalized(),
+        }
+    }
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct Transaction {
-    signature: String,
-    slot: u64,
-    err: Option<String>,
-    block_time: Option<i64>, //The estimated production time, as Unix timestamp (seconds since the Unix epoch). It's null if not available
-    fee: u64,
-    sender: String,
-    receiver: String,
-    amount: i64,
+    pub(crate) signature: String,
+    pub(crate) slot: u64,
+    #[serde(default)]
+    pub(crate) epoch: u64, //stamped by the store at ingest
+    pub(crate) commitment: Commitment,
+    pub(crate) tx_type: TxType,
+    pub(crate) err: Option<String>,
+    pub(crate) err_kind: Option<String>,
+    pub(crate) err_instruction_index: Option<u8>,
+    pub(crate) err_instruction_kind: Option<String>,
+    pub(crate) err_custom_code: Option<u32>,
+    pub(crate) err_program: Option<String>,
+    pub(crate) block_time: Option<i64>, //The estimated production time, as Unix timestamp (seconds since the Unix epoch). It's null if not available
+    pub(crate) fee: u64,
+    pub(crate) sender: String,
+    pub(crate) receiver: String,
+    pub(crate) amount: i64,
+    pub(crate) compute_units_consumed: Option<u64>,
+    pub(crate) compute_unit_limit: Option<u32>,
+    pub(crate) compute_unit_price: Option<u64>, //in micro-lamports per compute unit, i.e. the priority fee rate
+    pub(crate) num_signatures: u32,
+    pub(crate) num_instructions: u32,
+    pub(crate) log_messages: Option<Vec<String>>,
+    pub(crate) account_keys: Vec<String>, //static keys followed by lookup-table loaded writable and readonly keys
+    pub(crate) program_ids: Vec<String>,  //distinct programs invoked by top-level instructions
 }
 
 impl Transaction {
@@ -19,7 +78,15 @@ impl Transaction {
         Schema::new(vec![
             Field::new("signature", DataType::Utf8, false),
             Field::new("slot", DataType::UInt64, false),
+            Field::new("epoch", DataType::UInt64, false),
+            Field::new("commitment", DataType::Utf8, false),
+            Field::new("tx_type", DataType::Utf8, false),
             Field::new("err", DataType::Utf8, true),
+            Field::new("err_kind", DataType::Utf8, true),
+            Field::new("err_instruction_index", DataType::UInt8, true),
+            Field::new("err_instruction_kind", DataType::Utf8, true),
+            Field::new("err_custom_code", DataType::UInt32, true),
+            Field::new("err_program", DataType::Utf8, true),
             Field::new(
                 "block_time",
                 DataType::Timestamp(TimeUnit::Second, None),
@@ -29,14 +96,69 @@ impl Transaction {
             Field::new("sender", DataType::Utf8, false),
             Field::new("receiver", DataType::Utf8, false),
             Field::new("amount", DataType::Int64, false),
+            Field::new("compute_units_consumed", DataType::UInt64, true),
+            Field::new("compute_unit_limit", DataType::UInt32, true),
+            Field::new("compute_unit_price", DataType::UInt64, true),
+            Field::new("num_signatures", DataType::UInt32, false),
+            Field::new("num_instructions", DataType::UInt32, false),
+            Field::new_list(
+                "log_messages",
+                Field::new("item", DataType::Utf8, true),
+                true,
+            ),
+            Field::new_list(
+                "account_keys",
+                Field::new("item", DataType::Utf8, true),
+                false,
+            ),
+            Field::new_list(
+                "program_ids",
+                Field::new("item", DataType::Utf8, true),
+                false,
+            ),
         ])
     }
 }
 
+#[cfg(test)]
+impl Transaction {
+    /// A successful finalized call from `A` to `B` for the tests, which override the
+    /// fields they are about
+    pub(crate) fn mock(signature: &str, slot: u64) -> Self {
+        Self {
+            signature: signature.to_string(),
+            slot,
+            epoch: 0,
+            commitment: Commitment::Finalized,
+            tx_type: TxType::ProgramCall,
+            err: None,
+            err_kind: None,
+            err_instruction_index: None,
+            err_instruction_kind: None,
+            err_custom_code: None,
+            err_program: None,
+            block_time: None,
+            fee: 5000,
+            sender: "A".to_string(),
+            receiver: "B".to_string(),
+            amount: 0,
+            compute_units_consumed: None,
+            compute_unit_limit: None,
+            compute_unit_price: None,
+            num_signatures: 1,
+            num_instructions: 1,
+            log_messages: None,
+            account_keys: vec!["A".to_string(), "B".to_string()],
+            program_ids: vec!["11111111111111111111111111111111".to_string()],
+        }
+    }
+}
+
 pub(crate) fn parse_transaction(
     tx: &EncodedTransactionWithStatusMeta,
     slot: u64,
     block_time: Option<i64>,
+    commitment: Commitment,
 ) -> Option<Transaction> {
     let meta = tx.meta.as_ref()?;
     // println!("---transaction: {:#?}", tx.transaction);
@@ -49,19 +171,318 @@ pub(crate) fn parse_transaction(
     let receiver = message.static_account_keys().get(1)?.to_string();
 
     let amount = *meta.pre_balances.get(0)? as i64 - *meta.post_balances.get(0)? as i64;
+    let mut account_keys: Vec<String> = message
+        .static_account_keys()
+        .iter()
+        .map(|k| k.to_string())
+        .collect();
+    if let Some(loaded) = Option::<&UiLoadedAddresses>::from(meta.loaded_addresses.as_ref()) {
+        account_keys.extend(loaded.writable.iter().cloned());
+        account_keys.extend(loaded.readonly.iter().cloned());
+    }
+    let mut program_ids: Vec<String> = vec![];
+    for ix in message.instructions() {
+        if let Some(pid) = account_keys.get(ix.program_id_index as usize) {
+            if !program_ids.contains(pid) {
+                program_ids.push(pid.clone());
+            }
+        }
+    }
+    let (compute_unit_limit, compute_unit_price) = parse_compute_budget(&message);
+    let tx_type = TxType::classify(&message);
+    let tx_err = meta
+        .err
+        .as_ref()
+        .map(|e| TxError::from_transaction_error(e, &message))
+        .unwrap_or_default();
 
     Some(Transaction {
         signature,
         slot: slot,
+        epoch: 0,
+        commitment,
+        tx_type,
         err: meta.err.as_ref().map(|e| e.to_string()),
+        err_kind: tx_err.kind,
+        err_instruction_index: tx_err.instruction_index,
+        err_instruction_kind: tx_err.instruction_kind,
+        err_custom_code: tx_err.custom_code,
+        err_program: tx_err.program,
         block_time: block_time,
         fee: meta.fee,
         sender,
         receiver,
         amount,
+        compute_units_consumed: meta.compute_units_consumed.clone().into(),
+        compute_unit_limit,
+        compute_unit_price,
+        num_signatures: transaction.signatures.len() as u32,
+        num_instructions: message.instructions().len() as u32,
+        log_messages: Option::<&Vec<String>>::from(meta.log_messages.as_ref()).cloned(),
+        account_keys,
+        program_ids,
     })
 }
 
+/// Coarse classification of a transaction by the programs its top-level instructions invoke
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TxType {
+    Vote,
+    SystemTransfer,
+    System,
+    Stake,
+    Token,
+    ProgramCall,
+}
+
+impl TxType {
+    fn classify(message: &VersionedMessage) -> Self {
+        //NOTE: system program Transfer is the u32 LE variant tag 2
+        const SYSTEM_TRANSFER: [u8; 4] = [2, 0, 0, 0];
+
+        let keys = message.static_account_keys();
+        let ixs: Vec<_> = message
+            .instructions()
+            .iter()
+            .filter_map(|ix| Some((keys.get(ix.program_id_index as usize)?, ix)))
+            .filter(|(pid, _)| **pid != compute_budget::id())
+            .collect();
+        let invokes = |p: &Pubkey| ixs.iter().any(|(pid, _)| *pid == p);
+
+        if invokes(&vote::program::id()) {
+            TxType::Vote
+        } else if invokes(&TOKEN_PROGRAM_ID)
+            || invokes(&TOKEN_2022_PROGRAM_ID)
+            || invokes(&ASSOCIATED_TOKEN_PROGRAM_ID)
+        {
+            TxType::Token
+        } else if invokes(&stake::program::id()) {
+            TxType::Stake
+        } else if !ixs.is_empty() && ixs.iter().all(|(pid, _)| **pid == system_program::id()) {
+            if ixs
+                .iter()
+                .all(|(_, ix)| ix.data.starts_with(&SYSTEM_TRANSFER))
+            {
+                TxType::SystemTransfer
+            } else {
+                TxType::System
+            }
+        } else {
+            TxType::ProgramCall
+        }
+    }
+}
+
+/// Structured decoding of a `TransactionError`, flattened into nullable columns
+#[derive(Debug, Default, PartialEq)]
+struct TxError {
+    kind: Option<String>,
+    instruction_index: Option<u8>,
+    instruction_kind: Option<String>,
+    custom_code: Option<u32>,
+    program: Option<String>,
+}
+
+impl TxError {
+    fn from_transaction_error(err: &TransactionError, message: &VersionedMessage) -> Self {
+        let mut tx_err = TxError {
+            kind: Some(variant_name(err)),
+            ..Default::default()
+        };
+        if let TransactionError::InstructionError(index, ix_err) = err {
+            tx_err.instruction_index = Some(*index);
+            tx_err.instruction_kind = Some(variant_name(ix_err));
+            if let InstructionError::Custom(code) = ix_err {
+                tx_err.custom_code = Some(*code);
+            }
+            //NOTE the program id of an instruction is always a static key
+            tx_err.program = message
+                .instructions()
+                .get(*index as usize)
+                .and_then(|ix| {
+                    message
+                        .static_account_keys()
+                        .get(ix.program_id_index as usize)
+                })
+                .map(|pid| pid.to_string());
+        }
+        tx_err
+    }
+}
+
+/// Returns the bare enum variant name, e.g. `Custom` for `Custom(1)`
+fn variant_name<T: std::fmt::Debug>(v: &T) -> String {
+    format!("{:?}", v)
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .collect()
+}
+
+/// Extracts the (unit limit, unit price) pair set by ComputeBudget instructions, if any
+fn parse_compute_budget(message: &VersionedMessage) -> (Option<u32>, Option<u64>) {
+    //NOTE decode the borsh layout by hand: a one-byte variant tag followed by the LE payload
+    const SET_COMPUTE_UNIT_LIMIT: u8 = 2;
+    const SET_COMPUTE_UNIT_PRICE: u8 = 3;
+
+    let keys = message.static_account_keys();
+    let mut limit = None;
+    let mut price = None;
+    for ix in message.instructions() {
+        if keys.get(ix.program_id_index as usize) != Some(&compute_budget::id()) {
+            continue;
+        }
+        match ix.data.split_first() {
+            Some((&SET_COMPUTE_UNIT_LIMIT, data)) => {
+                limit = data.try_into().ok().map(u32::from_le_bytes);
+            }
+            Some((&SET_COMPUTE_UNIT_PRICE, data)) => {
+                price = data.try_into().ok().map(u64::from_le_bytes);
+            }
+            _ => {}
+        }
+    }
+    (limit, price)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Block {
+    slot: u64,
+    #[serde(default)]
+    pub(crate) epoch: u64,
+    commitment: Commitment,
+    blockhash: String,
+    previous_blockhash: String,
+    pub(crate) parent_slot: u64,
+    block_height: Option<u64>,
+    block_time: Option<i64>,
+    tx_count: u64,
+    success_count: u64,
+    failed_count: u64,
+    total_fees: u64,
+    leader: Option<String>, //the recipient of the block's fee reward
+}
+
+impl Block {
+    pub(crate) fn get_arrow_scheme() -> Schema {
+        Schema::new(vec![
+            Field::new("slot", DataType::UInt64, false),
+            Field::new("epoch", DataType::UInt64, false),
+            Field::new("commitment", DataType::Utf8, false),
+            Field::new("blockhash", DataType::Utf8, false),
+            Field::new("previous_blockhash", DataType::Utf8, false),
+            Field::new("parent_slot", DataType::UInt64, false),
+            Field::new("block_height", DataType::UInt64, true),
+            Field::new(
+                "block_time",
+                DataType::Timestamp(TimeUnit::Second, None),
+                true,
+            ),
+            Field::new("tx_count", DataType::UInt64, false),
+            Field::new("success_count", DataType::UInt64, false),
+            Field::new("failed_count", DataType::UInt64, false),
+            Field::new("total_fees", DataType::UInt64, false),
+            Field::new("leader", DataType::Utf8, true),
+        ])
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Reward {
+    slot: u64,
+    #[serde(default)]
+    pub(crate) epoch: u64,
+    commitment: Commitment,
+    pubkey: String,
+    lamports: i64,
+    post_balance: u64,
+    reward_type: Option<String>,
+    commission: Option<u8>,
+}
+
+impl Reward {
+    pub(crate) fn get_arrow_scheme() -> Schema {
+        Schema::new(vec![
+            Field::new("slot", DataType::UInt64, false),
+            Field::new("epoch", DataType::UInt64, false),
+            Field::new("commitment", DataType::Utf8, false),
+            Field::new("pubkey", DataType::Utf8, false),
+            Field::new("lamports", DataType::Int64, false),
+            Field::new("post_balance", DataType::UInt64, false),
+            Field::new("reward_type", DataType::Utf8, true),
+            Field::new("commission", DataType::UInt8, true),
+        ])
+    }
+}
+
+pub(crate) fn parse_block(
+    block: &UiConfirmedBlock,
+    slot: u64,
+    commitment: Commitment,
+) -> (Block, Vec<Reward>) {
+    let metas: Vec<_> = block
+        .transactions
+        .iter()
+        .flatten()
+        .filter_map(|tx| tx.meta.as_ref())
+        .collect();
+    let failed_count = metas.iter().filter(|m| m.err.is_some()).count() as u64;
+    let rewards = block.rewards.as_deref().unwrap_or_default();
+    let leader = rewards
+        .iter()
+        .find(|r| r.reward_type == Some(RewardType::Fee))
+        .map(|r| r.pubkey.clone());
+
+    let b = Block {
+        slot,
+        epoch: 0,
+        commitment,
+        blockhash: block.blockhash.clone(),
+        previous_blockhash: block.previous_blockhash.clone(),
+        parent_slot: block.parent_slot,
+        block_height: block.block_height,
+        block_time: block.block_time,
+        tx_count: metas.len() as u64,
+        success_count: metas.len() as u64 - failed_count,
+        failed_count,
+        total_fees: metas.iter().map(|m| m.fee).sum(),
+        leader,
+    };
+    let rs = rewards
+        .iter()
+        .map(|r| Reward {
+            slot,
+            epoch: 0,
+            commitment,
+            pubkey: r.pubkey.clone(),
+            lamports: r.lamports,
+            post_balance: r.post_balance,
+            reward_type: r.reward_type.map(|t| t.to_string()),
+            commission: r.commission,
+        })
+        .collect();
+    (b, rs)
+}
+
+/// All the rows parsed from one fetched block, before they are batched into the store
+#[derive(Debug)]
+pub(crate) struct SlotData {
+    pub(crate) commitment: Commitment,
+    pub(crate) transactions: Vec<Transaction>,
+    pub(crate) block: Block,
+    pub(crate) rewards: Vec<Reward>,
+}
+
+pub(crate) fn to_record_batch<T: Serialize>(
+    rows: &[T],
+    schema: Schema,
+) -> eyre::Result<RecordBatch> {
+    let schema = Arc::new(schema);
+    let mut decoder = ReaderBuilder::new(schema.clone()).build_decoder()?;
+    decoder.serialize(rows)?;
+    Ok(decoder.flush()?.unwrap_or(RecordBatch::new_empty(schema)))
+}
+
 #[allow(unused)]
 //NOTE parts from solana_sdk::account::Account
 #[derive(Debug, Serialize, Deserialize)]
@@ -78,9 +499,86 @@ struct Account {
 #[cfg(test)]
 mod unit_tests {
     use crate::fetch::{SolFetcher, SOL_RPC_URL};
+    use solana_sdk::{
+        compute_budget::ComputeBudgetInstruction, message::Message, pubkey::Pubkey,
+        system_instruction,
+    };
 
     use super::*;
 
+    #[test]
+    fn test_parse_compute_budget() {
+        let payer = Pubkey::new_unique();
+        let ixs = vec![
+            ComputeBudgetInstruction::set_compute_unit_limit(200_000),
+            ComputeBudgetInstruction::set_compute_unit_price(1_000),
+            system_instruction::transfer(&payer, &Pubkey::new_unique(), 42),
+        ];
+        let message = VersionedMessage::Legacy(Message::new(&ixs, Some(&payer)));
+        assert_eq!(parse_compute_budget(&message), (Some(200_000), Some(1_000)));
+
+        let ixs = vec![system_instruction::transfer(
+            &payer,
+            &Pubkey::new_unique(),
+            42,
+        )];
+        let message = VersionedMessage::Legacy(Message::new(&ixs, Some(&payer)));
+        assert_eq!(parse_compute_budget(&message), (None, None));
+    }
+
+    #[test]
+    fn test_tx_type_classify() {
+        let payer = Pubkey::new_unique();
+        let transfer = system_instruction::transfer(&payer, &Pubkey::new_unique(), 42);
+        let ixs = vec![
+            ComputeBudgetInstruction::set_compute_unit_price(1_000),
+            transfer.clone(),
+        ];
+        let message = VersionedMessage::Legacy(Message::new(&ixs, Some(&payer)));
+        assert_eq!(TxType::classify(&message), TxType::SystemTransfer);
+
+        let ixs = vec![system_instruction::allocate(&payer, 64)];
+        let message = VersionedMessage::Legacy(Message::new(&ixs, Some(&payer)));
+        assert_eq!(TxType::classify(&message), TxType::System);
+
+        let mut vote_ix = transfer.clone();
+        vote_ix.program_id = vote::program::id();
+        let message = VersionedMessage::Legacy(Message::new(&[vote_ix], Some(&payer)));
+        assert_eq!(TxType::classify(&message), TxType::Vote);
+
+        let mut call_ix = transfer;
+        call_ix.program_id = Pubkey::new_unique();
+        let message = VersionedMessage::Legacy(Message::new(&[call_ix], Some(&payer)));
+        assert_eq!(TxType::classify(&message), TxType::ProgramCall);
+    }
+
+    #[test]
+    fn test_tx_error_decoding() {
+        let payer = Pubkey::new_unique();
+        let ixs = vec![
+            ComputeBudgetInstruction::set_compute_unit_limit(200_000),
+            system_instruction::transfer(&payer, &Pubkey::new_unique(), 42),
+        ];
+        let message = VersionedMessage::Legacy(Message::new(&ixs, Some(&payer)));
+
+        let err = TransactionError::InstructionError(1, InstructionError::Custom(6001));
+        let tx_err = TxError::from_transaction_error(&err, &message);
+        assert_eq!(tx_err.kind.as_deref(), Some("InstructionError"));
+        assert_eq!(tx_err.instruction_index, Some(1));
+        assert_eq!(tx_err.instruction_kind.as_deref(), Some("Custom"));
+        assert_eq!(tx_err.custom_code, Some(6001));
+        assert_eq!(
+            tx_err.program,
+            Some(solana_sdk::system_program::id().to_string())
+        );
+
+        let err = TransactionError::BlockhashNotFound;
+        let tx_err = TxError::from_transaction_error(&err, &message);
+        assert_eq!(tx_err.kind.as_deref(), Some("BlockhashNotFound"));
+        assert_eq!(tx_err.instruction_index, None);
+        assert_eq!(tx_err.custom_code, None);
+    }
+
     #[test]
     fn test_parse_transaction() -> eyre::Result<()> {
         let sol_fetcher = SolFetcher::new(SOL_RPC_URL);
@@ -96,15 +594,30 @@ mod unit_tests {
             .transactions
             .unwrap() //FIXME
             .iter()
-            .filter_map(|tx| parse_transaction(tx, slot, block.block_time))
+            .filter_map(|tx| parse_transaction(tx, slot, block.block_time, Commitment::Finalized))
             .collect();
 
         assert_eq!(transactions.len(), 15);
         for tx in &transactions {
             assert_eq!(tx.slot, slot);
+            assert!(tx.num_signatures >= 1);
         }
         //FIXME this may be hold true always
         assert_eq!(transactions[0].signature, "CZkAA4a27zv58tYMsT7NXsvsUsakzouJXuAmbzWuV23XMYtHVQ9SbZvFXaSNLgJJTqtNQWoebYYtYHrZMUKmdPQ");
         Ok(())
     }
+
+    #[test]
+    fn test_parse_block() -> eyre::Result<()> {
+        let sol_fetcher = SolFetcher::new(SOL_RPC_URL);
+        let slot = 311_516_666;
+        let block = sol_fetcher.fetch_sol_block_sync(slot)?;
+        let (b, rewards) = parse_block(&block, slot, Commitment::Finalized);
+        assert_eq!(b.slot, slot);
+        assert!(b.parent_slot < slot);
+        assert_eq!(b.tx_count, b.success_count + b.failed_count);
+        assert!(b.leader.is_some());
+        assert!(rewards.iter().all(|r| r.slot == slot));
+        Ok(())
+    }
 }
@@ -0,0 +1,499 @@
+use async_trait::async_trait;
+use datafusion::{
+    arrow::{
+        array::{AsArray, RecordBatch},
+        compute::concat_batches,
+        datatypes::{Schema, SchemaRef, UInt64Type},
+    },
+    datasource::{MemTable, TableProvider, TableType},
+    error::Result,
+    execution::context::SessionState,
+    logical_expr::{AggregateFunction, Expr},
+    physical_plan::{memory::MemoryExec, ExecutionPlan},
+    prelude::{col, lit, DataFrame, SessionContext},
+    sql::{
+        parser::{DFParser, Statement as DFStatement},
+        sqlparser::ast::{
+            visit_expressions, DuplicateTreatment, Expr as SqlExpr, FunctionArguments, GroupByExpr,
+            SelectItem, SetExpr, Statement, TableFactor,
+        },
+    },
+};
+use serde::Serialize;
+use std::{
+    any::Any,
+    collections::{BTreeSet, HashSet},
+    ops::ControlFlow,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+/// Aggregates whose partial results over disjoint rows merge into the result over all of
+/// them, with the aggregate merging them
+const MERGEABLE: [(&str, &str); 4] = [
+    ("count", "sum"),
+    ("sum", "sum"),
+    ("min", "min"),
+    ("max", "max"),
+];
+/// Slots this far below the newest folded in one are settled: a finalized slot arriving
+/// that late is skipped
+const SLOT_HORIZON: u64 = 4096;
+
+/// A named aggregate query over the finalized `transactions`, kept up to date by folding
+/// in the rows of each finalized slot instead of re-running it over the whole store.
+/// The query is a single `SELECT ... FROM transactions [WHERE ...] [GROUP BY ...]` whose
+/// aggregates are `count`, `sum`, `min` or `max`.
+/// The result covers every slot finalized since the view was created, plus the ones
+/// stored at creation: rows the retention policy evicts stay counted.
+#[derive(Debug)]
+pub(crate) struct ContinuousView {
+    pub(crate) name: String,
+    pub(crate) sql: String,
+    /// folds the current result and a slot's partial result, over the table `state`
+    merge_sql: String,
+    schema: SchemaRef,
+    state: RwLock<ViewState>,
+}
+
+#[derive(Debug)]
+struct ViewState {
+    rows: RecordBatch,
+    //NOTE finalized slots may arrive out of order, e.g. a backfilled slot after a newer
+    //     confirmed one was promoted: every slot below `settled` counts as folded in,
+    //     `slots` holds the ones folded in above it, so at most SLOT_HORIZON of them
+    settled: u64,
+    slots: BTreeSet<u64>,
+    /// number of slots folded in
+    folded: usize,
+}
+
+impl ViewState {
+    fn new(rows: RecordBatch, slots: HashSet<u64>) -> Self {
+        let mut state = Self {
+            rows,
+            settled: 0,
+            slots: BTreeSet::new(),
+            folded: 0,
+        };
+        let mut slots: Vec<u64> = slots.into_iter().collect();
+        slots.sort_unstable();
+        for slot in slots {
+            state.insert(slot);
+        }
+        state
+    }
+
+    fn covers(&self, slot: u64) -> bool {
+        slot < self.settled || self.slots.contains(&slot)
+    }
+
+    fn insert(&mut self, slot: u64) {
+        if self.covers(slot) {
+            return;
+        }
+        self.slots.insert(slot);
+        self.folded += 1;
+        if let Some(newest) = self.slots.last().copied() {
+            self.settled = self.settled.max((newest + 1).saturating_sub(SLOT_HORIZON));
+            self.slots = self.slots.split_off(&self.settled);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ViewInfo {
+    pub name: String,
+    pub sql: String,
+    pub columns: Vec<String>,
+    pub rows: usize,
+    pub slots: usize,
+}
+
+impl ContinuousView {
+    /// Validates `sql` and computes the view over `source`, the finalized transactions
+    pub(crate) async fn create(name: &str, sql: &str, source: DataFrame) -> eyre::Result<Self> {
+        let merges = merge_functions(sql)?;
+        let (schema, batches, slots) = run_full(sql, source).await?;
+        let schema = Arc::new(nullable(&schema));
+        let columns = schema
+            .fields()
+            .iter()
+            .zip(&merges)
+            .map(|(f, merge)| {
+                let column = quote(f.name());
+                match merge {
+                    Some(merge) => format!("{merge}({column}) AS {column}"),
+                    None => column,
+                }
+            })
+            .collect::<Vec<_>>();
+        let keys = schema
+            .fields()
+            .iter()
+            .zip(&merges)
+            .filter(|(_, merge)| merge.is_none())
+            .map(|(f, _)| quote(f.name()))
+            .collect::<Vec<_>>();
+        let mut merge_sql = format!("SELECT {} FROM state", columns.join(", "));
+        if !keys.is_empty() {
+            merge_sql += &format!(" GROUP BY {}", keys.join(", "));
+        }
+        let rows = with_schema(&schema, &batches)?;
+        Ok(Self {
+            name: name.to_string(),
+            sql: sql.to_string(),
+            merge_sql,
+            schema,
+            state: RwLock::new(ViewState::new(rows, slots)),
+        })
+    }
+
+    pub(crate) fn rows(&self) -> RecordBatch {
+        self.state().rows.clone()
+    }
+
+    pub(crate) fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    pub(crate) fn info(&self) -> ViewInfo {
+        let state = self.state();
+        ViewInfo {
+            name: self.name.clone(),
+            sql: self.sql.clone(),
+            columns: self
+                .schema
+                .fields()
+                .iter()
+                .map(|f| f.name().clone())
+                .collect(),
+            rows: state.rows.num_rows(),
+            slots: state.folded,
+        }
+    }
+
+    /// Folds in the finalized transactions of `slot`, once
+    pub(crate) async fn apply(&self, slot: u64, transactions: &RecordBatch) -> eyre::Result<()> {
+        if transactions.num_rows() == 0 || self.state().covers(slot) {
+            return Ok(());
+        }
+        self.fold(&[slot], transactions).await
+    }
+
+    /// Folds in the stored slots of `source` not folded in yet, after finalized slots were
+    /// missed. Returns the number of slots folded in; missed slots evicted since are lost.
+    pub(crate) async fn catch_up(&self, source: DataFrame) -> eyre::Result<usize> {
+        let settled = self.state().settled;
+        let ctx = SessionContext::new();
+        let source = source.filter(col("slot").gt_eq(lit(settled)))?;
+        ctx.register_table("transactions", source.into_view())?;
+        let mut missed = vec![];
+        for batch in ctx
+            .sql("SELECT DISTINCT slot FROM transactions")
+            .await?
+            .collect()
+            .await?
+        {
+            let slots = batch.column(0).as_primitive::<UInt64Type>();
+            let state = self.state();
+            missed.extend(slots.values().iter().filter(|s| !state.covers(**s)));
+        }
+        if missed.is_empty() {
+            return Ok(0);
+        }
+        missed.sort_unstable();
+        let rows = ctx
+            .table("transactions")
+            .await?
+            .filter(col("slot").in_list(missed.iter().map(|s| lit(*s)).collect(), false))?;
+        let schema = Arc::new(rows.schema().as_arrow().clone());
+        let rows = concat_batches(&schema, &rows.collect().await?)?;
+        self.fold(&missed, &rows).await?;
+        Ok(missed.len())
+    }
+
+    /// Merges the partial result over `transactions`, the rows of `slots`, into the state
+    async fn fold(&self, slots: &[u64], transactions: &RecordBatch) -> eyre::Result<()> {
+        let ctx = SessionContext::new();
+        ctx.register_batch("transactions", transactions.clone())?;
+        let partial = ctx.sql(&self.sql).await?.collect().await?;
+        let partial = with_schema(&self.schema, &partial)?;
+
+        //NOTE applied one at a time by the maintainer, so the state does not move meanwhile
+        let current = self.rows();
+        let ctx = SessionContext::new();
+        let state = MemTable::try_new(self.schema.clone(), vec![vec![current, partial]])?;
+        ctx.register_table("state", Arc::new(state))?;
+        let merged = ctx.sql(&self.merge_sql).await?.collect().await?;
+        let rows = with_schema(&self.schema, &merged)?;
+
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        state.rows = rows;
+        for slot in slots {
+            state.insert(*slot);
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> std::sync::RwLockReadGuard<'_, ViewState> {
+        self.state.read().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Runs `sql` over every row of `source`, with the slots it covered
+async fn run_full(
+    sql: &str,
+    source: DataFrame,
+) -> eyre::Result<(Schema, Vec<RecordBatch>, HashSet<u64>)> {
+    let ctx = SessionContext::new();
+    ctx.register_table("transactions", source.into_view())?;
+    let df = ctx.sql(sql).await?;
+    let schema = df.schema().as_arrow().clone();
+    let rows = df.collect().await?;
+    let mut slots = HashSet::new();
+    for batch in ctx
+        .sql("SELECT DISTINCT slot FROM transactions")
+        .await?
+        .collect()
+        .await?
+    {
+        slots.extend(
+            batch
+                .column(0)
+                .as_primitive::<UInt64Type>()
+                .values()
+                .iter()
+                .copied(),
+        );
+    }
+    Ok((schema, rows, slots))
+}
+
+/// Concatenates `batches` under `schema`, which only differs from theirs in nullability
+fn with_schema(schema: &SchemaRef, batches: &[RecordBatch]) -> eyre::Result<RecordBatch> {
+    let batches = batches
+        .iter()
+        .map(|b| RecordBatch::try_new(schema.clone(), b.columns().to_vec()))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(concat_batches(schema, &batches)?)
+}
+
+fn nullable(schema: &Schema) -> Schema {
+    Schema::new(
+        schema
+            .fields()
+            .iter()
+            .map(|f| f.as_ref().clone().with_nullable(true))
+            .collect::<Vec<_>>(),
+    )
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// For each selected column of `sql`, the aggregate merging its partial results, none for
+/// the grouping keys. Fails for queries whose result can not be maintained by merging.
+fn merge_functions(sql: &str) -> eyre::Result<Vec<Option<&'static str>>> {
+    let unsupported = |why: &str| eyre::eyre!("not a continuous query, {}: {}", why, sql);
+    let mut statements = DFParser::parse_sql(sql)?;
+    let (Some(DFStatement::Statement(statement)), None) =
+        (statements.pop_front(), statements.pop_front())
+    else {
+        return Err(unsupported("expected a single SELECT"));
+    };
+    let Statement::Query(query) = *statement else {
+        return Err(unsupported("expected a single SELECT"));
+    };
+    if query.with.is_some()
+        || !query.order_by.is_empty()
+        || query.limit.is_some()
+        || query.offset.is_some()
+        || query.fetch.is_some()
+    {
+        return Err(unsupported(
+            "WITH, ORDER BY, LIMIT and OFFSET are not supported",
+        ));
+    }
+    let SetExpr::Select(select) = *query.body else {
+        return Err(unsupported("expected a single SELECT"));
+    };
+    if select.distinct.is_some() || select.having.is_some() {
+        return Err(unsupported("DISTINCT and HAVING are not supported"));
+    }
+    let from_transactions = match select.from.as_slice() {
+        [from] => {
+            from.joins.is_empty()
+                && matches!(&from.relation, TableFactor::Table { name, .. }
+                    if name.to_string().eq_ignore_ascii_case("transactions"))
+        }
+        _ => false,
+    };
+    if !from_transactions {
+        return Err(unsupported("only the transactions table can be queried"));
+    }
+    let GroupByExpr::Expressions(group_by, ..) = &select.group_by else {
+        return Err(unsupported("GROUP BY ALL is not supported"));
+    };
+
+    let mut merges = vec![];
+    let mut keys = HashSet::new();
+    for (i, item) in select.projection.iter().enumerate() {
+        let (expr, alias) = match item {
+            SelectItem::UnnamedExpr(expr) => (expr, None),
+            SelectItem::ExprWithAlias { expr, alias } => (expr, Some(alias.value.clone())),
+            _ => return Err(unsupported("wildcards are not supported")),
+        };
+        let merge = mergeable(expr);
+        if merge.is_none() && has_aggregate(expr) {
+            return Err(unsupported(
+                "only count, sum, min and max, not nested in expressions, are supported",
+            ));
+        }
+        if merge.is_none() {
+            keys.insert(expr.to_string());
+            keys.insert((i + 1).to_string());
+            keys.extend(alias);
+        }
+        merges.push(merge);
+    }
+    //NOTE a group not in the result would be merged into another one
+    if let Some(g) = group_by.iter().find(|g| !keys.contains(&g.to_string())) {
+        return Err(unsupported(&format!("GROUP BY {} is not selected", g)));
+    }
+    Ok(merges)
+}
+
+fn mergeable(expr: &SqlExpr) -> Option<&'static str> {
+    let SqlExpr::Function(f) = expr else {
+        return None;
+    };
+    let distinct = matches!(&f.args, FunctionArguments::List(l)
+        if l.duplicate_treatment == Some(DuplicateTreatment::Distinct));
+    if distinct || f.over.is_some() {
+        return None;
+    }
+    let name = f.name.to_string().to_lowercase();
+    MERGEABLE
+        .iter()
+        .find(|(aggregate, _)| *aggregate == name)
+        .map(|(_, merge)| *merge)
+}
+
+fn has_aggregate(expr: &SqlExpr) -> bool {
+    let ctx = SessionContext::new();
+    let found = visit_expressions(expr, |e| match e {
+        SqlExpr::Function(f) => {
+            let name = f.name.to_string().to_lowercase();
+            let aggregate = MERGEABLE.iter().any(|(a, _)| *a == name)
+                || AggregateFunction::from_str(&name).is_ok()
+                || ctx.udaf(&name).is_ok();
+            if aggregate {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        }
+        _ => ControlFlow::Continue(()),
+    });
+    found.is_break()
+}
+
+/// A continuous view as a table of the store's context
+pub(crate) struct ViewTable {
+    view: Arc<ContinuousView>,
+}
+
+impl ViewTable {
+    pub(crate) fn new(view: Arc<ContinuousView>) -> Self {
+        Self { view }
+    }
+}
+
+#[async_trait]
+impl TableProvider for ViewTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.view.schema()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::View
+    }
+
+    async fn scan(
+        &self,
+        _state: &SessionState,
+        projection: Option<&Vec<usize>>,
+        _filters: &[Expr],
+        _limit: Option<usize>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        let rows = self.view.rows();
+        let exec = MemoryExec::try_new(&[vec![rows]], self.schema(), projection.cloned())?;
+        Ok(Arc::new(exec))
+    }
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+
+    #[test]
+    fn test_view_slots() {
+        let rows = RecordBatch::new_empty(Arc::new(Schema::empty()));
+        let mut state = ViewState::new(rows, (1..=10).collect());
+        assert_eq!((state.folded, state.settled), (10, 0));
+        //late slots within the horizon are still folded in
+        state.insert(SLOT_HORIZON);
+        assert!(!state.covers(11));
+        state.insert(11);
+        assert_eq!(state.folded, 12);
+
+        //the tracked slots stay bounded, older ones count as settled
+        for slot in SLOT_HORIZON..SLOT_HORIZON * 4 {
+            state.insert(slot);
+        }
+        assert!(state.slots.len() <= SLOT_HORIZON as usize);
+        assert!(state.covers(12));
+        assert!(!state.covers(SLOT_HORIZON * 4));
+        let folded = state.folded;
+        state.insert(12);
+        assert_eq!(state.folded, folded);
+    }
+
+    #[test]
+    fn test_merge_functions() -> eyre::Result<()> {
+        assert_eq!(
+            merge_functions(
+                "SELECT date_trunc('minute', block_time) AS minute, count(1) AS n, sum(fee), max(fee) \
+                 FROM transactions WHERE err IS NULL GROUP BY minute"
+            )?,
+            vec![None, Some("sum"), Some("sum"), Some("max")]
+        );
+        assert_eq!(
+            merge_functions("SELECT sender, min(amount) FROM transactions GROUP BY 1")?,
+            vec![None, Some("min")]
+        );
+        assert_eq!(
+            merge_functions("SELECT count(1) FROM transactions")?,
+            vec![Some("sum")]
+        );
+        for sql in [
+            "SELECT sender, avg(fee) FROM transactions GROUP BY sender",
+            "SELECT sender, sum(fee) / count(1) FROM transactions GROUP BY sender",
+            "SELECT count(DISTINCT sender) FROM transactions",
+            "SELECT sum(fee) FROM transactions GROUP BY sender",
+            "SELECT sender, count(1) FROM transactions GROUP BY sender ORDER BY 2 DESC LIMIT 10",
+            "SELECT leader, count(1) FROM blocks GROUP BY leader",
+            "SELECT * FROM transactions",
+            "DROP TABLE transactions",
+        ] {
+            assert!(merge_functions(sql).is_err(), "{}", sql);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,507 @@
+use crate::subscribe::TxSubscription;
+use chrono::Utc;
+use datafusion::arrow::array::RecordBatch;
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{self, error::TrySendError},
+        RwLock,
+    },
+    task::JoinHandle,
+};
+
+const HOOKS_FILE: &str = "webhooks.json";
+const DEAD_LETTER_FILE: &str = "dead_letters.ndjson";
+const SIGNATURE_HEADER: &str = "x-solagg-signature";
+const WEBHOOK_ID_HEADER: &str = "x-solagg-webhook-id";
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const HOOK_QUEUE_CAPACITY: usize = 256; //NOTE in slots
+
+/// A registered webhook: matching transactions are POSTed to `url` once finalized
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Webhook {
+    pub(crate) id: String,
+    pub(crate) url: String,
+    /// key of the HMAC-SHA256 signature of each request body
+    pub(crate) secret: String,
+    #[serde(flatten)]
+    pub(crate) filter: TxSubscription,
+    pub(crate) created_at: String,
+}
+
+/// A webhook as listed by the API, without its secret
+#[derive(Debug, Serialize)]
+pub(crate) struct WebhookInfo<'a> {
+    id: &'a str,
+    url: &'a str,
+    #[serde(flatten)]
+    filter: &'a TxSubscription,
+    created_at: &'a str,
+}
+
+impl Webhook {
+    pub(crate) fn info(&self) -> WebhookInfo<'_> {
+        WebhookInfo {
+            id: &self.id,
+            url: &self.url,
+            filter: &self.filter,
+            created_at: &self.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct NewWebhook {
+    pub(crate) url: String,
+    pub(crate) secret: String,
+    #[serde(flatten)]
+    pub(crate) filter: TxSubscription,
+}
+
+impl NewWebhook {
+    pub(crate) fn validate(&self) -> eyre::Result<()> {
+        let url = reqwest::Url::parse(&self.url)?;
+        if !matches!(url.scheme(), "http" | "https") {
+            eyre::bail!("unsupported webhook url: {}", self.url);
+        }
+        if self.secret.is_empty() {
+            eyre::bail!("a webhook needs a secret to sign its requests");
+        }
+        self.filter.validate()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct StoredHooks {
+    next_id: u64,
+    hooks: BTreeMap<String, Webhook>,
+}
+
+/// The registered webhooks, persisted as JSON under `dir` on every change. Without a
+/// `dir`, hooks only live in memory and failed deliveries are only logged.
+#[derive(Debug, Default)]
+pub struct WebhookRegistry {
+    dir: Option<PathBuf>,
+    stored: StoredHooks,
+}
+
+pub type ThreadSafeWebhooks = Arc<RwLock<WebhookRegistry>>;
+
+impl WebhookRegistry {
+    pub fn load(dir: PathBuf) -> eyre::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(HOOKS_FILE);
+        let stored = if path.exists() {
+            serde_json::from_slice(&std::fs::read(&path)?)?
+        } else {
+            StoredHooks::default()
+        };
+        Ok(Self {
+            dir: Some(dir),
+            stored,
+        })
+    }
+
+    pub(crate) fn list(&self) -> Vec<Webhook> {
+        self.stored.hooks.values().cloned().collect()
+    }
+
+    //NOTE changes are saved before they are applied, a failed save changes nothing
+    pub(crate) fn create(&mut self, new: NewWebhook) -> eyre::Result<Webhook> {
+        new.validate()?;
+        let mut stored = self.stored.clone();
+        stored.next_id += 1;
+        let hook = Webhook {
+            id: format!("wh{}", stored.next_id),
+            url: new.url,
+            secret: new.secret,
+            filter: new.filter,
+            created_at: Utc::now().to_rfc3339(),
+        };
+        stored.hooks.insert(hook.id.clone(), hook.clone());
+        self.save(&stored)?;
+        self.stored = stored;
+        Ok(hook)
+    }
+
+    /// Whether a webhook was deleted
+    pub(crate) fn delete(&mut self, id: &str) -> eyre::Result<bool> {
+        let mut stored = self.stored.clone();
+        if stored.hooks.remove(id).is_none() {
+            return Ok(false);
+        }
+        self.save(&stored)?;
+        self.stored = stored;
+        Ok(true)
+    }
+
+    pub(crate) fn dead_letter_path(&self) -> Option<PathBuf> {
+        self.dir.as_ref().map(|d| d.join(DEAD_LETTER_FILE))
+    }
+
+    fn save(&self, stored: &StoredHooks) -> eyre::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        //NOTE written aside then renamed, so a crash never leaves a truncated registry
+        let tmp = dir.join(format!("{}.tmp", HOOKS_FILE));
+        std::fs::write(&tmp, serde_json::to_vec_pretty(stored)?)?;
+        std::fs::rename(tmp, dir.join(HOOKS_FILE))?;
+        Ok(())
+    }
+}
+
+/// How a delivery is retried before it goes to the dead-letter log
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_attempts: u32,
+    pub(crate) initial_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(500),
+        }
+    }
+}
+
+/// `sha256=<hex>`, the HMAC-SHA256 of `body` keyed by the webhook's secret
+pub(crate) fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+/// POSTs `body` to the webhook, retrying with exponential backoff. A delivery failing
+/// every attempt is appended to the dead-letter log.
+pub(crate) async fn deliver(
+    client: &reqwest::Client,
+    hook: &Webhook,
+    body: String,
+    retry: RetryPolicy,
+    dead_letters: Option<&Path>,
+) -> eyre::Result<()> {
+    let signature = sign(&hook.secret, body.as_bytes());
+    let mut delay = retry.initial_delay;
+    let mut error = String::new();
+    for attempt in 1..=retry.max_attempts {
+        let response = client
+            .post(&hook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .header(WEBHOOK_ID_HEADER, &hook.id)
+            .timeout(DELIVERY_TIMEOUT)
+            .body(body.clone())
+            .send()
+            .await;
+        match response {
+            Ok(r) if r.status().is_success() => return Ok(()),
+            Ok(r) => error = format!("status {}", r.status()),
+            Err(e) => error = e.to_string(),
+        }
+        debug!(
+            "webhook {} delivery failed (attempt {}): {}",
+            hook.id, attempt, error
+        );
+        if attempt < retry.max_attempts {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    warn!("webhook {} delivery dead-lettered: {}", hook.id, error);
+    dead_letter(dead_letters, hook, retry.max_attempts, &error, &body).await
+}
+
+/// Appends an undelivered body to the dead-letter log, if there is one
+async fn dead_letter(
+    path: Option<&Path>,
+    hook: &Webhook,
+    attempts: u32,
+    error: &str,
+    body: &str,
+) -> eyre::Result<()> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    let letter = json!({
+        "webhook_id": hook.id,
+        "url": hook.url,
+        "attempts": attempts,
+        "error": error,
+        "failed_at": Utc::now().to_rfc3339(),
+        "body": body,
+    });
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(format!("{}\n", letter).as_bytes()).await?;
+    Ok(())
+}
+
+/// Delivers the transactions of every finalized slot to the webhooks they match, rows
+/// of confirmed slots are only sent once finalized so a rollback never has to be undone.
+///NOTE each hook has a bounded queue drained in order by its own task, a slow or failing
+///     target never holds up ingest nor the other webhooks; what a full queue can not
+///     take goes straight to the dead-letter log
+pub(crate) fn spawn_dispatcher(
+    mut rx: broadcast::Receiver<(u64, RecordBatch)>,
+    webhooks: ThreadSafeWebhooks,
+) -> JoinHandle<()> {
+    let client = reqwest::Client::new();
+    tokio::spawn(async move {
+        let mut queues: HashMap<String, mpsc::Sender<String>> = HashMap::new();
+        loop {
+            let (slot, batch) = match rx.recv().await {
+                Ok(finalized) => finalized,
+                Err(RecvError::Lagged(slots)) => {
+                    warn!("webhooks missed the transactions of {} slots", slots);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let (hooks, dead_letters) = {
+                let registry = webhooks.read().await;
+                (registry.list(), registry.dead_letter_path())
+            };
+            //NOTE the queue of a deleted hook is closed, its task ends once drained
+            queues.retain(|id, _| hooks.iter().any(|hook| &hook.id == id));
+            for hook in hooks {
+                let rows = match hook.filter.matching_rows(&batch) {
+                    Ok(rows) if rows.is_empty() => continue,
+                    Ok(rows) => rows,
+                    Err(e) => {
+                        warn!("webhook {} filter failed: {}", hook.id, e);
+                        continue;
+                    }
+                };
+                let body = format!(
+                    r#"{{"webhook_id":{},"transactions":[{}]}}"#,
+                    json!(hook.id),
+                    rows.join(",")
+                );
+                let queue = queues.entry(hook.id.clone()).or_insert_with(|| {
+                    spawn_delivery(client.clone(), hook.clone(), dead_letters.clone())
+                });
+                let body = match queue.try_send(body) {
+                    Ok(()) => continue,
+                    Err(TrySendError::Full(body) | TrySendError::Closed(body)) => body,
+                };
+                warn!(
+                    "webhook {} queue full, slot {} dead-lettered",
+                    hook.id, slot
+                );
+                if let Err(e) =
+                    dead_letter(dead_letters.as_deref(), &hook, 0, "queue full", &body).await
+                {
+                    warn!("webhook {} dead letter failed: {}", hook.id, e);
+                }
+            }
+        }
+    })
+}
+
+/// Starts the delivery task of a webhook, fed by the returned queue
+fn spawn_delivery(
+    client: reqwest::Client,
+    hook: Webhook,
+    dead_letters: Option<PathBuf>,
+) -> mpsc::Sender<String> {
+    let (tx, mut rx) = mpsc::channel::<String>(HOOK_QUEUE_CAPACITY);
+    tokio::spawn(async move {
+        while let Some(body) = rx.recv().await {
+            let retry = RetryPolicy::default();
+            if let Err(e) = deliver(&client, &hook, body, retry, dead_letters.as_deref()).await {
+                warn!("webhook {} dead letter failed: {}", hook.id, e);
+            }
+        }
+    });
+    tx
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::types::{to_record_batch, Transaction};
+    use axum::{extract::State, http::HeaderMap, routing::post, Router};
+    use hyper::StatusCode;
+    use std::sync::Mutex;
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+    /// A local HTTP stand-in for a webhook target, answering every request with `status`
+    async fn stand_in(status: StatusCode) -> eyre::Result<(String, Received)> {
+        let received = Received::default();
+        let app =
+            Router::new()
+                .route(
+                    "/hook",
+                    post(
+                        move |State(received): State<Received>,
+                              headers: HeaderMap,
+                              body: String| async move {
+                            received.lock().unwrap().push((headers, body));
+                            status
+                        },
+                    ),
+                )
+                .with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/hook", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok((url, received))
+    }
+
+    #[tokio::test]
+    async fn test_registry() -> eyre::Result<()> {
+        let dir = std::env::temp_dir().join(format!("solagg_hooks_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut registry = WebhookRegistry::load(dir.clone())?;
+        let new = |url: &str| NewWebhook {
+            url: url.to_string(),
+            secret: "s3cret".to_string(),
+            filter: TxSubscription {
+                account: Some("B".to_string()),
+                ..Default::default()
+            },
+        };
+        let hook = registry.create(new("http://127.0.0.1:1/hook"))?;
+        assert!(new("ftp://127.0.0.1/hook").validate().is_err());
+        assert!(registry.create(new("ftp://127.0.0.1/hook")).is_err());
+        registry.create(new("http://127.0.0.1:2/hook"))?;
+
+        //persisted, ids are never reused
+        let mut registry = WebhookRegistry::load(dir.clone())?;
+        assert_eq!(registry.list().len(), 2);
+        assert!(registry.delete(&hook.id)?);
+        assert!(!registry.delete(&hook.id)?);
+        let mut registry = WebhookRegistry::load(dir.clone())?;
+        assert_eq!(registry.create(new("http://127.0.0.1:3/hook"))?.id, "wh3");
+        assert!(!serde_json::to_string(&registry.list()[0].info())?.contains("s3cret"));
+
+        //a failed save leaves the registry as it was
+        std::fs::remove_dir_all(&dir)?;
+        assert!(registry.create(new("http://127.0.0.1:4/hook")).is_err());
+        assert!(registry.delete("wh3").is_err());
+        assert_eq!(registry.list().len(), 2);
+        assert_eq!(registry.stored.next_id, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dispatcher() -> eyre::Result<()> {
+        let (url, received) = stand_in(StatusCode::OK).await?;
+        let webhooks = ThreadSafeWebhooks::default();
+        webhooks.write().await.create(NewWebhook {
+            url,
+            secret: "s3cret".to_string(),
+            filter: TxSubscription {
+                account: Some("B".to_string()),
+                ..Default::default()
+            },
+        })?;
+        let (tx, rx) = broadcast::channel(16);
+        let dispatcher = spawn_dispatcher(rx, webhooks);
+        let txs = [
+            Transaction::mock("s1", 1),
+            Transaction {
+                account_keys: vec!["A".to_string(), "C".to_string()],
+                ..Transaction::mock("s2", 1)
+            },
+        ];
+        for slot in 1..=3 {
+            tx.send((
+                slot,
+                to_record_batch(&txs, Transaction::get_arrow_scheme())?,
+            ))?;
+        }
+        let delivered = tokio::time::timeout(Duration::from_secs(10), async {
+            while received.lock().unwrap().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        dispatcher.abort();
+        assert!(delivered.is_ok(), "not every slot delivered");
+        for (_, body) in received.lock().unwrap().iter() {
+            let body: serde_json::Value = serde_json::from_str(body)?;
+            assert_eq!(body["webhook_id"], "wh1");
+            assert_eq!(body["transactions"].as_array().map(Vec::len), Some(1));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deliver() -> eyre::Result<()> {
+        let client = reqwest::Client::new();
+        let retry = RetryPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(1),
+        };
+        let (url, received) = stand_in(StatusCode::OK).await?;
+        let hook = Webhook {
+            id: "wh1".to_string(),
+            url,
+            secret: "s3cret".to_string(),
+            filter: TxSubscription::default(),
+            created_at: Utc::now().to_rfc3339(),
+        };
+        let body = r#"{"webhook_id":"wh1","transactions":[]}"#.to_string();
+        deliver(&client, &hook, body.clone(), retry, None).await?;
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            let (headers, got) = &received[0];
+            assert_eq!(got, &body);
+            assert_eq!(
+                headers[SIGNATURE_HEADER].to_str()?,
+                sign("s3cret", body.as_bytes())
+            );
+        }
+
+        //a failing target is retried, then dead-lettered
+        let (url, received) = stand_in(StatusCode::INTERNAL_SERVER_ERROR).await?;
+        let hook = Webhook { url, ..hook };
+        let path = std::env::temp_dir().join(format!("solagg_dead_letters_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        deliver(&client, &hook, body.clone(), retry, Some(&path)).await?;
+        assert_eq!(received.lock().unwrap().len(), 3);
+        let letter: serde_json::Value =
+            serde_json::from_str(std::fs::read_to_string(&path)?.trim())?;
+        assert_eq!(letter["webhook_id"], "wh1");
+        assert_eq!(letter["body"], body.as_str());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign() {
+        //RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}